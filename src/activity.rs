@@ -0,0 +1,76 @@
+use std::{
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Notify;
+
+use crate::{
+    config::{IDLE_POLL_INTERVAL, OBSERVER_TIMEOUT, POLL_INTERVAL},
+    status::PrinterStatus,
+};
+
+static ACTIVITY: LazyLock<Activity> = LazyLock::new(Activity::default);
+
+pub fn activity() -> &'static Activity {
+    &ACTIVITY
+}
+
+/// Who is looking at the printer, the keep-alive loop polls slowly when nobody is
+#[derive(Default)]
+pub struct Activity {
+    last_request: Mutex<Option<Instant>>,
+    push_connected: AtomicBool,
+    observer_arrived: Notify,
+}
+
+impl Activity {
+    /// A web client asked for something
+    pub fn request(&self) {
+        let now = Instant::now();
+        let previous = self
+            .last_request
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .replace(now);
+        if previous.is_none_or(|x| now.duration_since(x) > OBSERVER_TIMEOUT) {
+            self.observer_arrived.notify_one();
+        }
+    }
+
+    /// A push subscriber (MQTT) connected or went away
+    pub fn set_push_connected(&self, connected: bool) {
+        if !self.push_connected.swap(connected, Ordering::Relaxed) && connected {
+            self.observer_arrived.notify_one();
+        }
+    }
+
+    pub fn observed(&self, now: Instant) -> bool {
+        self.push_connected.load(Ordering::Relaxed)
+            || self
+                .last_request
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .is_some_and(|x| now.duration_since(x) <= OBSERVER_TIMEOUT)
+    }
+
+    /// Resolves when someone starts looking while nobody was, cuts an idle wait short
+    pub async fn observer_arrived(&self) {
+        self.observer_arrived.notified().await
+    }
+}
+
+/// Fast while printing, heating or watched, slow otherwise
+pub fn poll_interval(status: &PrinterStatus, observed: bool) -> Duration {
+    let busy = matches!(status.status.as_str(), "RUNNING" | "PAUSED")
+        || status.nozzle_target_temperature > 0.0
+        || status.heated_bed_target_temperature > 0.0;
+    if busy || observed {
+        POLL_INTERVAL
+    } else {
+        IDLE_POLL_INTERVAL
+    }
+}
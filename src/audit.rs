@@ -0,0 +1,239 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use actix_web::HttpRequest;
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::API_KEY_USERS;
+
+/// Read from the end of the log at a time, until enough entries were found
+const READ_CHUNK: u64 = 64 * 1024;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    pub source: String,
+    pub user: Option<String>,
+    pub action: String,
+    pub parameters: Value,
+    pub success: bool,
+    pub response: String,
+}
+
+/// Append-only log of every state-changing request, one JSON object per line
+pub struct AuditLog {
+    path: PathBuf,
+    // serializes appends, so concurrent requests can't interleave lines
+    lock: Mutex<()>,
+}
+
+impl AuditLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn record<T, E: std::fmt::Display>(
+        &self,
+        req: &HttpRequest,
+        action: &str,
+        parameters: Value,
+        result: &Result<T, E>,
+    ) {
+        self.record_from(
+            source_address(req),
+            Some(api_key_user(req, API_KEY_USERS)),
+            action,
+            parameters,
+            result,
+        );
+    }
+
+    /// For commands that don't arrive over HTTP, e.g. MQTT
+    pub fn record_from<T, E: std::fmt::Display>(
+        &self,
+        source: String,
+        user: Option<String>,
+        action: &str,
+        parameters: Value,
+        result: &Result<T, E>,
+    ) {
+        let (success, response) = match result {
+            Ok(_) => (true, "OK".to_string()),
+            Err(e) => (false, e.to_string()),
+        };
+        self.append(AuditEntry {
+            timestamp: Utc::now(),
+            source,
+            user,
+            action: action.to_string(),
+            parameters,
+            success,
+            response,
+        });
+    }
+
+    pub fn append(&self, entry: AuditEntry) {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let line = match serde_json::to_string(&entry) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Failed to serialize audit entry {entry:?}: {e}");
+                return;
+            }
+        };
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{line}"));
+        if let Err(e) = written {
+            error!("Failed to write audit log {:?}: {e}", self.path);
+        }
+    }
+
+    /// Most recent entries first, reads only as much of the log as it needs
+    pub fn recent(&self, limit: usize) -> anyhow::Result<Vec<AuditEntry>> {
+        let mut file = match File::open(&self.path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut position = file.metadata()?.len();
+        let mut entries = Vec::new();
+        // start of a line that began in a chunk not read yet
+        let mut partial = Vec::new();
+        while entries.len() < limit && position > 0 {
+            let size = READ_CHUNK.min(position);
+            position -= size;
+            let mut buffer = vec![0; size as usize];
+            file.seek(SeekFrom::Start(position))?;
+            file.read_exact(&mut buffer)?;
+            buffer.append(&mut partial);
+            // the first line may go on in the chunk before, unless this is the start
+            let complete_from = match buffer.iter().position(|x| *x == b'\n') {
+                _ if position == 0 => 0,
+                Some(x) => x + 1,
+                None => {
+                    partial = buffer;
+                    continue;
+                }
+            };
+            partial = buffer[..complete_from].to_vec();
+            entries.extend(
+                buffer[complete_from..]
+                    .split(|x| *x == b'\n')
+                    .rev()
+                    .filter_map(|line| serde_json::from_slice::<AuditEntry>(line).ok())
+                    .take(limit - entries.len()),
+            );
+        }
+        Ok(entries)
+    }
+}
+
+fn source_address(req: &HttpRequest) -> String {
+    let peer = req
+        .peer_addr()
+        .map(|x| x.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    // keep what a reverse proxy claims, but never instead of the actual peer
+    match req
+        .headers()
+        .get("X-Forwarded-For")
+        .and_then(|x| x.to_str().ok())
+    {
+        Some(forwarded) => format!("{peer} (forwarded for {forwarded})"),
+        None => peer,
+    }
+}
+
+/// OctoPrint clients identify themselves with an API key, only one listed in
+/// `known` names a user, anything else could be made up
+fn api_key_user(req: &HttpRequest, known: &[(&str, &str)]) -> String {
+    let key = req.headers().get("X-Api-Key").and_then(|x| x.to_str().ok());
+    known
+        .iter()
+        .find(|(known_key, _)| Some(*known_key) == key)
+        .map_or("anonymous", |(_, user)| user)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use serde_json::json;
+
+    use super::*;
+
+    fn entry(action: String) -> AuditEntry {
+        AuditEntry {
+            timestamp: Utc::now(),
+            source: "127.0.0.1".to_string(),
+            user: None,
+            action,
+            parameters: json!({ "padding": "x".repeat(200) }),
+            success: true,
+            response: "OK".to_string(),
+        }
+    }
+
+    #[test]
+    fn recent_reads_from_the_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit = AuditLog::new(dir.path().join("audit.log"));
+        assert!(audit.recent(10).unwrap().is_empty());
+
+        // several chunks, so lines are split between them
+        for i in 0..1000 {
+            audit.append(entry(format!("action {i}")));
+        }
+        let actions = |limit| {
+            audit.recent(limit).unwrap().into_iter().map(|x| x.action).collect::<Vec<_>>()
+        };
+        assert_eq!(actions(3), ["action 999", "action 998", "action 997"]);
+        let all = actions(2000);
+        assert_eq!(all.len(), 1000);
+        assert!(all.iter().rev().enumerate().all(|(i, x)| *x == format!("action {i}")));
+        assert!(actions(0).is_empty());
+    }
+
+    #[test]
+    fn recent_skips_damaged_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit = AuditLog::new(dir.path().join("audit.log"));
+        audit.append(entry("first".to_string()));
+        OpenOptions::new()
+            .append(true)
+            .open(&audit.path)
+            .and_then(|mut file| writeln!(file, "{{not json"))
+            .unwrap();
+        audit.append(entry("second".to_string()));
+        let actions: Vec<_> = audit.recent(10).unwrap().into_iter().map(|x| x.action).collect();
+        assert_eq!(actions, ["second", "first"]);
+    }
+
+    #[test]
+    fn only_known_api_keys_name_a_user() {
+        let known = [("secret-key", "slicer")];
+        let user = |key: Option<&str>| {
+            let mut request = TestRequest::default();
+            if let Some(key) = key {
+                request = request.insert_header(("X-Api-Key", key));
+            }
+            api_key_user(&request.to_http_request(), &known)
+        };
+        assert_eq!(user(Some("secret-key")), "slicer");
+        assert_eq!(user(Some("made-up-key")), "anonymous");
+        assert_eq!(user(None), "anonymous");
+    }
+}
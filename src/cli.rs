@@ -0,0 +1,520 @@
+use std::{path::PathBuf, process::ExitCode, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand, ValueEnum};
+use reqwest::{
+    RequestBuilder, Response, StatusCode,
+    multipart::{Form, Part},
+};
+use serde_json::Value;
+
+use crate::{
+    config::{DISCOVER_PRINTER, DISCOVERY_ADDRESS, DISCOVERY_TIMEOUT},
+    discovery::{self, DiscoveredPrinter, discovery},
+    pairing::{Pairing, PairingState, pairing_loop},
+    snapmaker_client,
+    status::ConnectionState,
+    token_store,
+};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "OctoPrint compatible proxy for the Snapmaker 2.0")]
+pub struct Cli {
+    /// Go through the API of a running proxy, e.g. http://127.0.0.1:55533,
+    /// instead of talking to the printer
+    #[arg(long, global = true, env = "SM_PROXY_URL")]
+    pub proxy: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the proxy, the default without a subcommand
+    Serve,
+    /// Print the printer status as JSON, fails when the printer is offline
+    Status {
+        /// Print a summary for humans instead
+        #[arg(long)]
+        pretty: bool,
+    },
+    /// Send a G-code file to the printer
+    Upload {
+        file: PathBuf,
+        /// Start printing it right away
+        #[arg(long)]
+        print: bool,
+    },
+    /// Pause the running print
+    Pause,
+    /// Resume the paused print
+    Resume,
+    /// Stop the print, through a proxy only while its interlock allows it
+    Stop,
+    /// Set the enclosure light or fan, 0 to 100
+    Enclosure {
+        part: EnclosurePart,
+        #[arg(value_parser = clap::value_parser!(u8).range(0..=100))]
+        value: u8,
+    },
+    /// Pair with the printer, someone has to confirm on its touchscreen
+    Pair,
+    /// List the printers that answer on the network
+    Discover,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum EnclosurePart {
+    Light,
+    Fan,
+}
+
+/// Exit codes scripts can tell apart, clap exits with 2 on bad usage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Exit {
+    Failed = 1,
+    NotPaired = 3,
+    Unreachable = 4,
+    /// The proxy refused: controls locked, confirmation missing, wrong printer
+    /// state or too many commands
+    Refused = 5,
+}
+
+#[derive(Debug)]
+struct Failure {
+    exit: Exit,
+    message: String,
+}
+
+impl Failure {
+    fn new(exit: Exit, message: impl Into<String>) -> Self {
+        Self {
+            exit,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for Failure {
+    fn from(error: anyhow::Error) -> Self {
+        let unreachable = error
+            .chain()
+            .filter_map(|x| x.downcast_ref::<reqwest::Error>())
+            .any(|x| x.is_connect() || x.is_timeout());
+        let exit = if unreachable { Exit::Unreachable } else { Exit::Failed };
+        Self::new(exit, format!("{error:#}"))
+    }
+}
+
+/// Runs everything but `serve`
+pub(crate) async fn run(command: Command, proxy: Option<String>) -> ExitCode {
+    // an empty SM_PROXY_URL means the printer, like an unset one
+    let target = match proxy.filter(|x| !x.is_empty()) {
+        Some(url) => Target::Proxy(url.trim_end_matches('/').to_string()),
+        None => Target::Printer,
+    };
+    match target.run(command).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) => {
+            eprintln!("error: {}", failure.message);
+            ExitCode::from(failure.exit as u8)
+        }
+    }
+}
+
+enum Target {
+    /// Straight to the printer with the stored token, without the proxy's
+    /// interlock and rate limit
+    Printer,
+    Proxy(String),
+}
+
+impl Target {
+    async fn run(&self, command: Command) -> Result<(), Failure> {
+        match command {
+            Command::Serve => unreachable!("serve is run by main"),
+            Command::Status { pretty } => self.status(pretty).await,
+            Command::Upload { file, print } => self.upload(file, print).await,
+            Command::Pause => self.control("pause_print", "Print paused").await,
+            Command::Resume => self.control("resume_print", "Print resumed").await,
+            // asking for the stop is the confirmation
+            Command::Stop => self.control("stop_print?confirm=true", "Print stopped").await,
+            Command::Enclosure { part, value } => self.enclosure(part, value).await,
+            Command::Pair => self.pair().await,
+            Command::Discover => self.discover().await,
+        }
+    }
+
+    async fn status(&self, pretty: bool) -> Result<(), Failure> {
+        let status = match self {
+            Target::Printer => {
+                let token = printer_token().await?;
+                let mut status = snapmaker_client::get_status(&token).await?;
+                status.connection = ConnectionState::Connected;
+                serde_json::to_value(status).map_err(anyhow::Error::from)?
+            }
+            Target::Proxy(url) => {
+                let response = send(reqwest::Client::new().get(format!("{url}/api/status"))).await?;
+                response.json().await.map_err(anyhow::Error::from)?
+            }
+        };
+        if pretty {
+            print_status(&status);
+        } else {
+            println!("{status}");
+        }
+        if status["connection"] != "offline" && status["status"] != "UNKNOWN" {
+            return Ok(());
+        }
+        // an unpaired proxy reports the printer offline too
+        if let Target::Proxy(url) = self {
+            let response = send(reqwest::Client::new().get(format!("{url}/api/pairing"))).await?;
+            let pairing: Value = response.json().await.map_err(anyhow::Error::from)?;
+            if pairing["state"] != "paired" {
+                let message = "The proxy isn't paired with the printer";
+                return Err(Failure::new(Exit::NotPaired, message));
+            }
+        }
+        let reason = status["print_status"].as_str().unwrap_or("Offline");
+        Err(Failure::new(Exit::Unreachable, format!("Printer not available: {reason}")))
+    }
+
+    async fn upload(&self, file: PathBuf, print: bool) -> Result<(), Failure> {
+        let Some(file_name) = file.file_name().and_then(|x| x.to_str()).map(str::to_string) else {
+            return Err(Failure::new(Exit::Failed, format!("Not a file: {}", file.display())));
+        };
+        match self {
+            Target::Printer => {
+                let token = printer_token().await?;
+                snapmaker_client::upload_file_to_snapmaker(&token, &file, &file_name).await?;
+                if print {
+                    // like the proxy, give the printer a moment with the new file
+                    tokio::time::sleep(Duration::from_secs(2)).await;
+                    snapmaker_client::start_print(&token).await?;
+                }
+            }
+            Target::Proxy(url) => {
+                let content = std::fs::read(&file)
+                    .map_err(|e| Failure::new(Exit::Failed, format!("{}: {e}", file.display())))?;
+                let form = Form::new()
+                    .part("file", Part::bytes(content).file_name(file_name.clone()))
+                    .text("print", print.to_string());
+                let request = reqwest::Client::new()
+                    .post(format!("{url}/api/files/local"))
+                    .multipart(form);
+                send(request).await?;
+            }
+        }
+        match print {
+            true => println!("Uploaded {file_name}, printing"),
+            false => println!("Uploaded {file_name}"),
+        }
+        Ok(())
+    }
+
+    async fn control(&self, action: &str, done: &str) -> Result<(), Failure> {
+        match self {
+            Target::Printer => {
+                let token = printer_token().await?;
+                match action.split('?').next().unwrap_or_default() {
+                    "pause_print" => snapmaker_client::pause_print(&token).await?,
+                    "resume_print" => snapmaker_client::resume_print(&token).await?,
+                    _ => snapmaker_client::stop_print(&token).await?,
+                }
+            }
+            Target::Proxy(url) => {
+                send(reqwest::Client::new().post(format!("{url}/api/{action}"))).await?;
+            }
+        }
+        println!("{done}");
+        Ok(())
+    }
+
+    async fn enclosure(&self, part: EnclosurePart, value: u8) -> Result<(), Failure> {
+        match self {
+            Target::Printer => {
+                let token = printer_token().await?;
+                match part {
+                    EnclosurePart::Light => {
+                        snapmaker_client::set_enclosure_light(&token, value).await?
+                    }
+                    EnclosurePart::Fan => snapmaker_client::set_enclosure_fan(&token, value).await?,
+                }
+            }
+            Target::Proxy(url) => {
+                let path = match part {
+                    EnclosurePart::Light => "light",
+                    EnclosurePart::Fan => "fan",
+                };
+                let request = reqwest::Client::new()
+                    .post(format!("{url}/api/enclosure/{path}"))
+                    .form(&[("value", value)]);
+                send(request).await?;
+            }
+        }
+        println!("Enclosure {} set to {value}", format!("{part:?}").to_lowercase());
+        Ok(())
+    }
+
+    async fn pair(&self) -> Result<(), Failure> {
+        match self {
+            Target::Printer => pair_with_printer().await,
+            Target::Proxy(url) => pair_through_proxy(url).await,
+        }
+    }
+
+    async fn discover(&self) -> Result<(), Failure> {
+        let printers: Vec<DiscoveredPrinter> = match self {
+            Target::Printer => discovery::discover(DISCOVERY_ADDRESS, DISCOVERY_TIMEOUT).await?,
+            Target::Proxy(url) => {
+                let request = reqwest::Client::new().post(format!("{url}/api/discovery/scan"));
+                let found: Value = send(request).await?.json().await.map_err(anyhow::Error::from)?;
+                serde_json::from_value(found["printers"].clone()).map_err(anyhow::Error::from)?
+            }
+        };
+        if printers.is_empty() {
+            let message = format!("No printer answered on {DISCOVERY_ADDRESS}");
+            return Err(Failure::new(Exit::Unreachable, message));
+        }
+        for printer in printers {
+            println!(
+                "{}\t{}\t{}\t{}",
+                printer.name,
+                printer.endpoint(),
+                printer.model.as_deref().unwrap_or("-"),
+                printer.status.as_deref().unwrap_or("-"),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Sends a request to the proxy, refusals become the matching exit code
+async fn send(request: RequestBuilder) -> Result<Response, Failure> {
+    let response = request
+        .send()
+        .await
+        .map_err(|e| Failure::new(Exit::Unreachable, format!("Proxy not reachable: {e}")))?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    let exit = match status {
+        StatusCode::SERVICE_UNAVAILABLE if body.starts_with("Not paired") => Exit::NotPaired,
+        StatusCode::CONFLICT
+        | StatusCode::LOCKED
+        | StatusCode::PRECONDITION_REQUIRED
+        | StatusCode::TOO_MANY_REQUESTS => Exit::Refused,
+        // read-only proxies don't have the control endpoints at all
+        StatusCode::NOT_FOUND => {
+            return Err(Failure::new(Exit::Refused, "The proxy doesn't take this command"));
+        }
+        _ => Exit::Failed,
+    };
+    Err(Failure::new(exit, format!("{status}: {body}")))
+}
+
+/// Looks for `DISCOVER_PRINTER` once, the proxy's discovery loop doesn't run here
+async fn find_printer() -> Result<(), Failure> {
+    if let Some(name) = DISCOVER_PRINTER {
+        discovery().scan().await?;
+        if discovery().followed().is_none() {
+            let message = format!("Printer {name} not found on the network");
+            return Err(Failure::new(Exit::Unreachable, message));
+        }
+    }
+    Ok(())
+}
+
+/// The stored token, refreshed with the printer like the proxy does on start
+async fn printer_token() -> Result<String, Failure> {
+    find_printer().await?;
+    let not_paired =
+        |message: &str| Failure::new(Exit::NotPaired, format!("{message}, run `sm-proxy pair`"));
+    if token_store::load()?.is_none() {
+        return Err(not_paired("Not paired with the printer"));
+    }
+    match snapmaker_client::refresh_stored_token().await {
+        Ok(Some(token)) => Ok(token),
+        Ok(None) => Err(not_paired("The printer didn't accept the stored token")),
+        Err(e) => {
+            let unreachable = e
+                .downcast_ref::<reqwest::Error>()
+                .is_some_and(|x| x.is_connect() || x.is_timeout());
+            match unreachable {
+                true => Err(Failure::new(Exit::Unreachable, format!("Printer not reachable: {e}"))),
+                false => Err(Failure::new(Exit::Failed, e.to_string())),
+            }
+        }
+    }
+}
+
+/// Runs one pairing like the proxy does, but gives up instead of retrying
+async fn pair_with_printer() -> Result<(), Failure> {
+    find_printer().await?;
+    let pairing = Arc::new(Pairing::default());
+    let mut states = pairing.subscribe();
+    tokio::spawn(pairing_loop(pairing.clone(), false));
+    while states.changed().await.is_ok() {
+        let state = states.borrow_and_update().clone();
+        match state {
+            PairingState::Refreshing | PairingState::WaitingForToken { .. } => (),
+            PairingState::WaitingForApproval { expires, .. } => {
+                touchscreen_prompt(expires);
+            }
+            PairingState::Retrying { error, .. } => return Err(Failure::new(Exit::Failed, error)),
+            PairingState::Paired { .. } => {
+                println!("Paired, the token is in {}", token_store::token_path().display());
+                return Ok(());
+            }
+        }
+    }
+    Err(Failure::new(Exit::Failed, "Pairing stopped"))
+}
+
+/// Asks the proxy to pair now and follows its pairing page
+async fn pair_through_proxy(url: &str) -> Result<(), Failure> {
+    let client = reqwest::Client::new();
+    let pairing_state = || async {
+        let response = send(client.get(format!("{url}/api/pairing"))).await?;
+        response.json::<Value>().await.map_err(|e| Failure::from(anyhow::Error::from(e)))
+    };
+    let before = pairing_state().await?;
+    // a failure from before doesn't count, skip the wait for the next attempt
+    let failed_before = match before["state"] == "retrying" {
+        true => before["attempt"].as_u64(),
+        false => None,
+    };
+    if failed_before.is_some() {
+        send(client.post(format!("{url}/api/pairing/retry"))).await?;
+    }
+    let mut announced = false;
+    loop {
+        let state = pairing_state().await?;
+        match state["state"].as_str().unwrap_or_default() {
+            "paired" => {
+                println!("The proxy is paired with the printer");
+                return Ok(());
+            }
+            "waiting_for_approval" if !announced => {
+                let expires = serde_json::from_value(state["expires"].clone());
+                touchscreen_prompt(expires.unwrap_or_else(|_| Utc::now()));
+                announced = true;
+            }
+            "retrying" if state["attempt"].as_u64() > failed_before => {
+                let error = state["error"].as_str().unwrap_or("Pairing failed");
+                return Err(Failure::new(Exit::Failed, error));
+            }
+            _ => (),
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+fn touchscreen_prompt(expires: DateTime<Utc>) {
+    let expires = expires.format("%H:%M:%S UTC");
+    eprintln!("Tap \"Yes\" on the Snapmaker touchscreen, waiting until {expires}");
+}
+
+fn print_status(status: &Value) {
+    let text = |key: &str| status[key].as_str().unwrap_or("-").to_string();
+    let number = |key: &str| status[key].as_f64().unwrap_or_default();
+    println!("Printer   {} ({})", text("status"), text("connection"));
+    if matches!(status["status"].as_str(), Some("RUNNING" | "PAUSED")) {
+        println!("File      {} {:.0}%", text("file_name"), number("progress") * 100.0);
+        let remaining = number("remaining_time") as u64;
+        println!("Remaining {}h {:02}m", remaining / 3600, remaining % 3600 / 60);
+    }
+    println!(
+        "Nozzle    {:.1} / {:.1} °C",
+        number("nozzle_temperature"),
+        number("nozzle_target_temperature")
+    );
+    println!(
+        "Bed       {:.1} / {:.1} °C",
+        number("heated_bed_temperature"),
+        number("heated_bed_target_temperature")
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// Answers one request with `status` and `body`, returns its URL
+    async fn respond_once(status: &'static str, body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0; 4096];
+            let _ = stream.read(&mut buffer).await.unwrap();
+            let response = format!(
+                "HTTP/1.1 {status}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        url
+    }
+
+    async fn exit_for(status: &'static str, body: &'static str) -> Exit {
+        let url = respond_once(status, body).await;
+        send(reqwest::Client::new().post(url)).await.unwrap_err().exit
+    }
+
+    #[test]
+    fn exit_codes() {
+        let codes = [Exit::Failed, Exit::NotPaired, Exit::Unreachable, Exit::Refused];
+        assert_eq!(codes.map(|x| x as u8), [1, 3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn proxy_answers_map_to_exit_codes() {
+        let url = respond_once("200 OK", "{}").await;
+        assert!(send(reqwest::Client::new().get(url)).await.is_ok());
+
+        let not_paired = exit_for("503 Service Unavailable", "Not paired with the printer").await;
+        assert_eq!(not_paired, Exit::NotPaired);
+        assert_eq!(exit_for("503 Service Unavailable", "Draining").await, Exit::Failed);
+        for refused in [
+            "409 Conflict",
+            "423 Locked",
+            "428 Precondition Required",
+            "429 Too Many Requests",
+            "404 Not Found",
+        ] {
+            assert_eq!(exit_for(refused, "").await, Exit::Refused, "{refused}");
+        }
+        assert_eq!(exit_for("500 Internal Server Error", "").await, Exit::Failed);
+    }
+
+    #[tokio::test]
+    async fn unreachable_proxy() {
+        // bound and dropped, nothing listens there anymore
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let failure = send(reqwest::Client::new().get(&url)).await.unwrap_err();
+        assert_eq!(failure.exit, Exit::Unreachable);
+        assert!(failure.message.starts_with("Proxy not reachable"));
+
+        let error = reqwest::Client::new().get(&url).send().await.unwrap_err();
+        let failure = Failure::from(anyhow::Error::from(error).context("Uploading"));
+        assert_eq!(failure.exit, Exit::Unreachable);
+        assert!(failure.message.starts_with("Uploading: "));
+    }
+
+    #[test]
+    fn other_errors_fail() {
+        let failure = Failure::from(anyhow::anyhow!("disk full").context("Saving the token"));
+        assert_eq!(failure.exit, Exit::Failed);
+        assert_eq!(failure.message, "Saving the token: disk full");
+    }
+}
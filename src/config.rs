@@ -1,3 +1,152 @@
+use crate::email::SmtpConfig;
+use crate::machine::Machine;
+use crate::presets::Preset;
+use crate::thermal::{ThermalAction, ThermalConfig};
+use std::borrow::Cow;
+use crate::webhooks::WebhookTarget;
+use std::time::Duration;
+
 pub(crate) const SNAPMAKER_ENDPOINT: &str = "http://192.168.0.138:8080";
-pub(crate) const TOKEN_FILE: &str = "snapmaker_token.txt";
+// Find the printer with this name (as shown on its touchscreen) on the network
+// and follow it when its address changes, `None` always uses SNAPMAKER_ENDPOINT
+pub(crate) const DISCOVER_PRINTER: Option<&str> = None;
+// Where discovery requests are sent, the Snapmaker listens on UDP port 20054
+pub(crate) const DISCOVERY_ADDRESS: &str = "255.255.255.255:20054";
+// How long replies to a discovery request are collected
+pub(crate) const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(2);
+// Time between discoveries while following DISCOVER_PRINTER
+pub(crate) const DISCOVERY_INTERVAL: Duration = Duration::from_secs(60);
+// Name of the printer in metrics and other integrations
+pub(crate) const PRINTER_NAME: &str = "snapmaker";
+// Printer size for clamping jog moves, `None` takes the model the printer reports, or
+// assumes the smallest (A150) before it did
+pub(crate) const MACHINE: Option<Machine> = None;
+// Jog speed when a request doesn't give one, in mm/min
+pub(crate) const JOG_FEEDRATE: f64 = 3000.0;
+// Where the printer token is kept, `None` uses $STATE_DIRECTORY (systemd's
+// StateDirectory=), then $XDG_STATE_HOME/sm-proxy, then ~/.local/state/sm-proxy
+pub(crate) const STATE_DIR: Option<&str> = None;
+// Encrypts the stored token with the key in this file, 64 hex digits. The
+// SM_PROXY_TOKEN_KEY environment variable takes precedence, `None` and no
+// variable store it in plain text
+pub(crate) const TOKEN_KEY_FILE: Option<&str> = None;
+// How long a pairing waits for "Yes" on the touchscreen before asking again
+pub(crate) const PAIRING_APPROVAL_TIMEOUT: Duration = Duration::from_secs(120);
+// Wait between pairing attempts after a failure
+pub(crate) const PAIRING_RETRY_INTERVAL: Duration = Duration::from_secs(10);
 pub(crate) const SERVE_ADDRESS: &str = "127.0.0.1:55533";
+// Advertise SERVE_ADDRESS over mDNS as an OctoPrint instance so slicers and apps find it
+pub(crate) const MDNS_ADVERTISE: bool = true;
+// Name shown in slicers, `None` uses the model, e.g. "Snapmaker A350 (proxy)"
+pub(crate) const MDNS_NAME: Option<&str> = None;
+// Only serve status, rendering and file listings on SERVE_ADDRESS, no controls
+pub(crate) const READ_ONLY: bool = false;
+// Additional listener that is always read-only, `None` disables it
+pub(crate) const OBSERVER_ADDRESS: Option<&str> = None;
+// Every state-changing request is appended here as one JSON line
+pub(crate) const AUDIT_LOG_FILE: &str = "audit.log";
+// OctoPrint API keys of known clients and the user the audit log names for them,
+// requests with any other key are logged as anonymous
+pub(crate) const API_KEY_USERS: &[(&str, &str)] = &[];
+// Requests the Snapmaker gets at the same time, its web server is easily overwhelmed
+pub(crate) const MAX_PRINTER_REQUESTS: usize = 2;
+// Control commands each client may send: a burst, then this many per minute
+pub(crate) const COMMAND_BURST: u32 = 5;
+pub(crate) const COMMAND_RATE_PER_MINUTE: u32 = 30;
+// Time between status polls while the printer answers
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Time between status polls while nothing prints or heats and nobody watches,
+// short enough to keep the token session alive
+pub(crate) const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+// A web client counts as watching for this long after its last request
+pub(crate) const OBSERVER_TIMEOUT: Duration = Duration::from_secs(30);
+// Time between enclosure polls, it changes far slower than the toolhead
+pub(crate) const ENCLOSURE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+// The keep-alive loop counts as hung, and the systemd watchdog fires, when it
+// doesn't come back within this long after its planned wake-up
+pub(crate) const KEEP_ALIVE_HANG_TIMEOUT: Duration = Duration::from_secs(120);
+// /readyz fails once the last successful poll is older than this
+pub(crate) const READY_MAX_POLL_AGE: Duration = Duration::from_secs(30);
+// Longest wait between polls while the printer doesn't answer
+pub(crate) const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+// A printer that doesn't accept the connection this fast counts as unreachable
+pub(crate) const PRINTER_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Longest a request to the printer may take, so a hung one fails and backs off
+pub(crate) const PRINTER_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// Longest an upload to the printer may take, large files over WiFi are slow
+pub(crate) const PRINTER_UPLOAD_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+// A running print whose progress and position don't change this long counts as stalled
+pub(crate) const STALL_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+// Temperatures kept for the dashboard chart and /api/printer?history=true
+pub(crate) const TEMPERATURE_HISTORY_WINDOW: Duration = Duration::from_secs(30 * 60);
+// Where the temperature history is kept across restarts, `None` keeps it in memory only
+pub(crate) const TEMPERATURE_HISTORY_FILE: Option<&str> = Some("temperature_history.json");
+// How long a shutdown waits for uploads to the printer before aborting them
+pub(crate) const UPLOAD_DRAIN_TIMEOUT: Duration = Duration::from_secs(60);
+// How long a token from /api/stop_print/arm confirms a stop
+pub(crate) const STOP_CONFIRM_WINDOW: Duration = Duration::from_secs(30);
+// Kept while the controls are locked with /api/lock, so they stay locked across restarts
+pub(crate) const LOCK_FILE: &str = "controls.locked";
+
+// HTTPS listener address, `None` disables TLS
+pub(crate) const TLS_ADDRESS: Option<&str> = None;
+pub(crate) const TLS_CERT_FILE: &str = "tls/cert.pem";
+pub(crate) const TLS_KEY_FILE: &str = "tls/key.pem";
+// Generate (and keep) a self-signed certificate when the files above don't exist
+pub(crate) const TLS_SELF_SIGNED: bool = true;
+pub(crate) const TLS_SELF_SIGNED_NAMES: &[&str] = &["localhost", "127.0.0.1"];
+// Only answer with redirects to the HTTPS listener on SERVE_ADDRESS
+pub(crate) const TLS_REDIRECT_HTTP: bool = false;
+
+// MQTT broker (host, port) for Home Assistant, `None` disables MQTT
+pub(crate) const MQTT_BROKER: Option<(&str, u16)> = None;
+pub(crate) const MQTT_USERNAME: Option<&str> = None;
+pub(crate) const MQTT_PASSWORD: Option<&str> = None;
+pub(crate) const MQTT_TOPIC_PREFIX: &str = "sm-proxy";
+pub(crate) const MQTT_DISCOVERY_PREFIX: &str = "homeassistant";
+
+// Webhooks fired on print lifecycle events, e.g.
+// WebhookTarget { url: "https://ntfy.sh/my-printer", secret: None, format: crate::webhooks::WebhookFormat::Ntfy, events: &[] }
+pub(crate) const WEBHOOKS: &[WebhookTarget] = &[];
+pub(crate) const WEBHOOK_MAX_ATTEMPTS: u32 = 5;
+// Every delivery attempt is appended here as one JSON line
+pub(crate) const WEBHOOK_LOG_FILE: &str = "webhook_deliveries.log";
+
+// Emails on finished or cancelled prints and lost connections, `None` disables them, e.g.
+// SmtpConfig { host: "smtp.example.com", port: 587, tls: crate::email::SmtpTls::StartTls, username: Some("printer"), password: Some("secret"), from: "Snapmaker <printer@example.com>", to: &["team@example.com"] }
+pub(crate) const SMTP: Option<SmtpConfig> = None;
+
+// G-code commands the console accepts while a print is running or paused:
+// reporting, temperatures, fans, speed/flow overrides and display messages
+pub(crate) const GCODE_ALLOWED_WHILE_PRINTING: &[&str] = &[
+    "M105", "M114", "M115", "M119", "M104", "M140", "M106", "M107", "M220", "M221", "M117",
+    "M27", "M31",
+];
+// Console lines kept for the web terminal
+pub(crate) const CONSOLE_SCROLLBACK: usize = 500;
+
+// Preheat presets until they are edited through /api/presets, then PRESETS_FILE wins
+pub(crate) const DEFAULT_PRESETS: &[Preset] = &[
+    Preset { name: Cow::Borrowed("PLA"), nozzle: 210.0, bed: 60.0, enclosure_fan: None },
+    Preset { name: Cow::Borrowed("PETG"), nozzle: 240.0, bed: 80.0, enclosure_fan: None },
+    Preset { name: Cow::Borrowed("ABS"), nozzle: 250.0, bed: 100.0, enclosure_fan: Some(0) },
+    Preset { name: Cow::Borrowed("TPU"), nozzle: 225.0, bed: 50.0, enclosure_fan: None },
+];
+pub(crate) const PRESETS_FILE: &str = "presets.json";
+
+// Thermal watchdog, `None` disables it. Each anomaly is reported first and escalated
+// every `escalate_after` while it persists, up to the action configured for it
+pub(crate) const THERMAL_WATCHDOG: Option<ThermalConfig> = Some(ThermalConfig {
+    band: 15.0,
+    off_target_after: Duration::from_secs(120),
+    nozzle_heat_timeout: Duration::from_secs(5 * 60),
+    bed_heat_timeout: Duration::from_secs(10 * 60),
+    rise_threshold: 10.0,
+    rise_window: Duration::from_secs(60),
+    drop_threshold: 20.0,
+    escalate_after: Duration::from_secs(60),
+    off_target: ThermalAction::Pause,
+    slow_heating: ThermalAction::Notify,
+    rising_without_target: ThermalAction::Notify,
+    sudden_drop: ThermalAction::Pause,
+});
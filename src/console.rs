@@ -0,0 +1,55 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Sent,
+    Received,
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConsoleLine {
+    pub timestamp: DateTime<Utc>,
+    pub direction: Direction,
+    pub text: String,
+}
+
+/// Scrollback of the G-code terminal, shared by everyone using the web UI
+pub struct Console {
+    lines: Mutex<VecDeque<ConsoleLine>>,
+    capacity: usize,
+}
+
+impl Console {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            lines: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    pub fn push(&self, direction: Direction, text: &str) {
+        let mut lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        // multi-line answers get one entry per line
+        for text in text.lines().filter(|x| !x.trim().is_empty()) {
+            if lines.len() == self.capacity {
+                lines.pop_front();
+            }
+            lines.push_back(ConsoleLine {
+                timestamp: Utc::now(),
+                direction,
+                text: text.to_string(),
+            });
+        }
+    }
+
+    /// Oldest first
+    pub fn lines(&self) -> Vec<ConsoleLine> {
+        let lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        lines.iter().cloned().collect()
+    }
+}
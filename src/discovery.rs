@@ -0,0 +1,289 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::UdpSocket,
+    sync::{Notify, watch},
+    time::Instant,
+};
+
+use crate::{
+    config::{
+        DISCOVER_PRINTER, DISCOVERY_ADDRESS, DISCOVERY_INTERVAL, DISCOVERY_TIMEOUT,
+        IDLE_POLL_INTERVAL, SNAPMAKER_ENDPOINT,
+    },
+    shutdown::{Phase, shutdown},
+    status::PrinterStatus,
+};
+
+/// What the Snapmaker listens for on its discovery port
+const DISCOVERY_REQUEST: &[u8] = b"discover";
+/// Port of the HTTP API when a reply only carries an IP
+const API_PORT: u16 = 8080;
+
+static DISCOVERY: LazyLock<Discovery> = LazyLock::new(Discovery::default);
+
+pub fn discovery() -> &'static Discovery {
+    &DISCOVERY
+}
+
+/// The printer's HTTP API, where discovery last found `DISCOVER_PRINTER` or
+/// `SNAPMAKER_ENDPOINT`
+pub fn endpoint() -> String {
+    discovery()
+        .followed()
+        .unwrap_or_else(|| SNAPMAKER_ENDPOINT.to_string())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DiscoveredPrinter {
+    /// As set on the touchscreen
+    pub name: String,
+    pub address: SocketAddr,
+    pub model: Option<String>,
+    pub status: Option<String>,
+}
+
+impl DiscoveredPrinter {
+    /// A reply looks like `Snapmaker@192.168.1.20|model:Snapmaker 2 Model A350|status:IDLE`,
+    /// the address may carry the API port
+    pub fn parse(reply: &str) -> Option<Self> {
+        let mut parts = reply.trim().split('|');
+        let (name, address) = parts.next()?.rsplit_once('@')?;
+        let address = match address.parse::<SocketAddr>() {
+            Ok(x) => x,
+            Err(_) => SocketAddr::new(address.parse::<IpAddr>().ok()?, API_PORT),
+        };
+        let mut printer = Self {
+            name: name.to_string(),
+            address,
+            model: None,
+            status: None,
+        };
+        for part in parts {
+            match part.split_once(':') {
+                Some(("model", x)) => printer.model = Some(x.to_string()),
+                Some(("status", x)) => printer.status = Some(x.to_string()),
+                _ => (),
+            }
+        }
+        Some(printer)
+    }
+
+    pub fn endpoint(&self) -> String {
+        format!("http://{}", self.address)
+    }
+}
+
+/// Printers found by the last scan and where the followed one is
+pub struct Discovery {
+    printers: Mutex<Vec<DiscoveredPrinter>>,
+    last_scan: Mutex<Option<DateTime<Utc>>>,
+    followed: watch::Sender<Option<String>>,
+    rescan: Notify,
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Self {
+            printers: Mutex::default(),
+            last_scan: Mutex::default(),
+            followed: watch::Sender::new(None),
+            rescan: Notify::new(),
+        }
+    }
+}
+
+impl Discovery {
+    pub fn printers(&self) -> Vec<DiscoveredPrinter> {
+        self.printers.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn last_scan(&self) -> Option<DateTime<Utc>> {
+        *self.last_scan.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Endpoint of `DISCOVER_PRINTER`, `None` until it was found
+    pub fn followed(&self) -> Option<String> {
+        self.followed.borrow().clone()
+    }
+
+    /// Resolves once `DISCOVER_PRINTER` was found
+    pub async fn found(&self) {
+        let mut receiver = self.followed.subscribe();
+        let _ = receiver.wait_for(Option::is_some).await;
+    }
+
+    /// Asks the discovery loop to look again now, e.g. when the printer stopped answering
+    pub fn rescan(&self) {
+        self.rescan.notify_one();
+    }
+
+    /// Broadcasts, keeps what answered and follows `DISCOVER_PRINTER` to its new address
+    pub async fn scan(&self) -> anyhow::Result<Vec<DiscoveredPrinter>> {
+        let printers = discover(DISCOVERY_ADDRESS, DISCOVERY_TIMEOUT).await?;
+        *self.printers.lock().unwrap_or_else(|e| e.into_inner()) = printers.clone();
+        *self.last_scan.lock().unwrap_or_else(|e| e.into_inner()) = Some(Utc::now());
+        if let Some(name) = DISCOVER_PRINTER
+            && let Some(printer) = printers.iter().find(|x| x.name.eq_ignore_ascii_case(name))
+        {
+            let endpoint = printer.endpoint();
+            self.followed.send_if_modified(|followed| {
+                let moved = followed.as_ref() != Some(&endpoint);
+                if moved {
+                    info!("Found printer {name} at {endpoint}");
+                    *followed = Some(endpoint);
+                }
+                moved
+            });
+        }
+        Ok(printers)
+    }
+}
+
+/// Sends one discovery request to `target`, usually `DISCOVERY_ADDRESS`, and
+/// collects the replies until `timeout`
+pub async fn discover(target: &str, timeout: Duration) -> anyhow::Result<Vec<DiscoveredPrinter>> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.set_broadcast(true)?;
+    socket.send_to(DISCOVERY_REQUEST, target).await?;
+    let deadline = Instant::now() + timeout;
+    let mut printers: Vec<DiscoveredPrinter> = Vec::new();
+    let mut buffer = [0; 1024];
+    loop {
+        let received = tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await;
+        let Ok(received) = received else {
+            break;
+        };
+        let (length, from) = received?;
+        let reply = String::from_utf8_lossy(&buffer[..length]);
+        match DiscoveredPrinter::parse(&reply) {
+            Some(printer) if !printers.contains(&printer) => printers.push(printer),
+            Some(_) => (),
+            None => debug!("Ignoring discovery reply from {from}: {reply}"),
+        }
+    }
+    printers.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(printers)
+}
+
+/// Follows `DISCOVER_PRINTER`, looking every `DISCOVERY_INTERVAL` and whenever asked
+pub(crate) async fn discovery_loop() {
+    let Some(name) = DISCOVER_PRINTER else {
+        return;
+    };
+    loop {
+        match discovery().scan().await {
+            Ok(_) if discovery().followed().is_none() => {
+                warn!("Printer {name} not found on the network");
+            }
+            Ok(_) => (),
+            Err(e) => warn!("Printer discovery failed: {e}"),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(DISCOVERY_INTERVAL) => (),
+            _ = discovery().rescan.notified() => (),
+            _ = shutdown().reached(Phase::Stopping) => return,
+        }
+    }
+}
+
+/// The reply of the printer we talk to, by name when following one
+fn ours(printers: &[DiscoveredPrinter]) -> Option<&DiscoveredPrinter> {
+    if let Some(name) = DISCOVER_PRINTER {
+        return printers.iter().find(|x| x.name.eq_ignore_ascii_case(name));
+    }
+    let endpoint = reqwest::Url::parse(SNAPMAKER_ENDPOINT).ok()?;
+    let host = endpoint.host_str()?;
+    printers.iter().find(|x| x.address.ip().to_string() == host)
+}
+
+/// Until paired, serves the state the printer announces to anyone on the
+/// network, the API itself tells nothing without a token
+pub(crate) async fn announced_status_loop(status_sender: &watch::Sender<PrinterStatus>) {
+    loop {
+        let status = match discovery().scan().await {
+            Ok(printers) => match ours(&printers) {
+                Some(x) => PrinterStatus::announced(
+                    x.status.as_deref().unwrap_or("UNKNOWN"),
+                    x.model.as_deref(),
+                ),
+                None => PrinterStatus::unavailable("Printer not paired"),
+            },
+            Err(e) => {
+                debug!("Printer discovery failed: {e}");
+                PrinterStatus::unavailable("Printer not paired")
+            }
+        };
+        let _ = status_sender.send_replace(status);
+        tokio::select! {
+            _ = tokio::time::sleep(IDLE_POLL_INTERVAL) => (),
+            _ = shutdown().reached(Phase::Stopping) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_replies() {
+        let printer = DiscoveredPrinter::parse(
+            "Snapmaker@192.168.1.20|model:Snapmaker 2 Model A350|status:IDLE\n",
+        )
+        .unwrap();
+        assert_eq!(printer.name, "Snapmaker");
+        assert_eq!(printer.endpoint(), "http://192.168.1.20:8080");
+        assert_eq!(printer.model.as_deref(), Some("Snapmaker 2 Model A350"));
+        assert_eq!(printer.status.as_deref(), Some("IDLE"));
+
+        let printer = DiscoveredPrinter::parse("Shop@2@10.0.0.5:8081|firmware:1.2").unwrap();
+        assert_eq!(printer.name, "Shop@2");
+        assert_eq!(printer.endpoint(), "http://10.0.0.5:8081");
+        assert_eq!((printer.model, printer.status), (None, None));
+
+        assert_eq!(DiscoveredPrinter::parse("Snapmaker"), None);
+        assert_eq!(DiscoveredPrinter::parse("Snapmaker@printer.local"), None);
+    }
+
+    #[tokio::test]
+    async fn collects_replies_from_a_responder() {
+        let responder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = responder.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut buffer = [0; 64];
+            let (length, from) = responder.recv_from(&mut buffer).await.unwrap();
+            assert_eq!(&buffer[..length], DISCOVERY_REQUEST);
+            for reply in [
+                "Workshop@127.0.0.1|model:Snapmaker 2 Model A250|status:RUNNING",
+                "Attic@127.0.0.2:8081|status:IDLE",
+                "not a printer",
+                // printers answer more than once
+                "Workshop@127.0.0.1|model:Snapmaker 2 Model A250|status:RUNNING",
+            ] {
+                responder.send_to(reply.as_bytes(), from).await.unwrap();
+            }
+        });
+
+        let printers = discover(&target, Duration::from_millis(500)).await.unwrap();
+        let names: Vec<_> = printers.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, ["Attic", "Workshop"]);
+        assert_eq!(printers[0].endpoint(), "http://127.0.0.2:8081");
+        assert_eq!(printers[1].status.as_deref(), Some("RUNNING"));
+    }
+
+    #[tokio::test]
+    async fn nothing_answers() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = silent.local_addr().unwrap().to_string();
+        let printers = discover(&target, Duration::from_millis(100)).await.unwrap();
+        assert_eq!(printers, []);
+    }
+}
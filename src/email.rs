@@ -0,0 +1,350 @@
+use std::{
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+    message::{Attachment, Mailbox, MultiPart, SinglePart, header::ContentType},
+    transport::smtp::authentication::Credentials,
+};
+use log::{error, info, warn};
+use tokio::sync::broadcast;
+
+use crate::{
+    config::{PRINTER_NAME, SMTP},
+    events::{EventKind, PrinterEvent, format_duration},
+    gcode::UploadedFile,
+};
+
+const NOTIFY_ON: &[EventKind] = &[
+    EventKind::PrintFinished,
+    EventKind::PrintCancelled,
+    EventKind::PrintStalled,
+    EventKind::PrinterDisconnected,
+];
+
+/// How the connection to the SMTP server is secured
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(
+    not(test),
+    allow(dead_code, reason = "constructed by `SMTP` in config.rs, which is off by default")
+)]
+pub enum SmtpTls {
+    /// Plain text, only for local relays and test sinks
+    None,
+    /// Upgrade a plain connection, usually port 587
+    StartTls,
+    /// TLS from the first byte, usually port 465
+    Tls,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SmtpConfig {
+    pub host: &'static str,
+    pub port: u16,
+    pub tls: SmtpTls,
+    pub username: Option<&'static str>,
+    pub password: Option<&'static str>,
+    pub from: &'static str,
+    pub to: &'static [&'static str],
+}
+
+/// Mails a summary when a print ends or the printer goes away
+pub(crate) async fn email_loop(
+    events: broadcast::Receiver<PrinterEvent>,
+    last_upload: Arc<Mutex<Option<UploadedFile>>>,
+) {
+    let Some(config) = SMTP else {
+        return;
+    };
+    run(config, events, last_upload).await
+}
+
+async fn run(
+    config: SmtpConfig,
+    mut events: broadcast::Receiver<PrinterEvent>,
+    last_upload: Arc<Mutex<Option<UploadedFile>>>,
+) {
+    let transport = match build_transport(&config) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Invalid SMTP configuration: {e}");
+            return;
+        }
+    };
+    let mut started: Option<(String, DateTime<Utc>)> = None;
+
+    loop {
+        let event = match events.recv().await {
+            Ok(x) => x,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("Email notifier skipped {skipped} events");
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        if event.kind == EventKind::PrintStarted {
+            started = Some((event.status.file_name.clone(), event.timestamp));
+            continue;
+        }
+        if !NOTIFY_ON.contains(&event.kind) {
+            continue;
+        }
+
+        let started_at = match &started {
+            Some((file_name, at)) if *file_name == event.status.file_name => Some(*at),
+            _ => None,
+        };
+        // the print may still go on after these
+        if !matches!(event.kind, EventKind::PrinterDisconnected | EventKind::PrintStalled) {
+            started = None;
+        }
+        let upload = last_upload
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+            .filter(|x| x.file_name == event.status.file_name);
+
+        let message = match build_message(&config, &event, started_at, upload) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Failed to build {:?} email: {e}", event.kind);
+                continue;
+            }
+        };
+        let transport = transport.clone();
+        let kind = event.kind;
+        tokio::spawn(async move {
+            match transport.send(message).await {
+                Ok(_) => info!("Sent {kind:?} email to {}", config.to.join(", ")),
+                Err(e) => error!("Failed to send {kind:?} email via {}: {e}", config.host),
+            }
+        });
+    }
+}
+
+fn build_transport(
+    config: &SmtpConfig,
+) -> Result<AsyncSmtpTransport<Tokio1Executor>, lettre::transport::smtp::Error> {
+    let builder = match config.tls {
+        SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(config.host),
+        SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(config.host)?,
+        SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(config.host)?,
+    };
+    let mut builder = builder.port(config.port);
+    if let (Some(username), Some(password)) = (config.username, config.password) {
+        builder = builder.credentials(Credentials::new(username.into(), password.into()));
+    }
+    Ok(builder.build())
+}
+
+fn build_message(
+    config: &SmtpConfig,
+    event: &PrinterEvent,
+    started_at: Option<DateTime<Utc>>,
+    upload: Option<UploadedFile>,
+) -> anyhow::Result<Message> {
+    let mut builder = Message::builder()
+        .from(config.from.parse::<Mailbox>()?)
+        .subject(format!("{PRINTER_NAME}: {}", event.kind.title()));
+    for to in config.to {
+        builder = builder.to(to.parse::<Mailbox>()?);
+    }
+
+    let text = SinglePart::plain(summary(event, started_at));
+    let message = match upload.and_then(|x| x.thumbnail.map(|t| (x.file_name, t))) {
+        Some((file_name, thumbnail)) => {
+            let stem = std::path::Path::new(&file_name)
+                .file_stem()
+                .map(|x| x.to_string_lossy().into_owned())
+                .unwrap_or(file_name);
+            let name = format!("{stem}.{}", thumbnail.extension());
+            let attachment = Attachment::new(name)
+                .body(thumbnail.data, ContentType::parse(thumbnail.mime)?);
+            builder.multipart(MultiPart::mixed().singlepart(text).singlepart(attachment))?
+        }
+        None => builder.singlepart(text)?,
+    };
+    Ok(message)
+}
+
+fn summary(event: &PrinterEvent, started_at: Option<DateTime<Utc>>) -> String {
+    let status = &event.status;
+    let mut out = String::new();
+    let _ = writeln!(out, "{}\n", event.message);
+    let _ = writeln!(out, "Printer:   {PRINTER_NAME}");
+    if !status.file_name.is_empty() {
+        let _ = writeln!(out, "File:      {}", status.file_name);
+    }
+    let _ = writeln!(out, "Progress:  {:.1}%", status.progress * 100.0);
+    if status.elapsed_time > 0.0 {
+        let _ = write!(out, "Duration:  {}", format_duration(status.elapsed_time));
+        if status.estimated_time > 0.0 {
+            let difference = status.elapsed_time - status.estimated_time;
+            let sign = if difference < 0.0 { "-" } else { "+" };
+            let _ = write!(
+                out,
+                " (estimated {}, {sign}{})",
+                format_duration(status.estimated_time),
+                format_duration(difference.abs())
+            );
+        }
+        let _ = writeln!(out);
+    }
+    if let Some(started_at) = started_at {
+        let wall_time = (event.timestamp - started_at).num_seconds() as f64;
+        let _ = writeln!(
+            out,
+            "Wall time: {} since {}",
+            format_duration(wall_time),
+            started_at.format("%Y-%m-%d %H:%M:%S UTC")
+        );
+    }
+    let _ = writeln!(
+        out,
+        "Nozzle:    {:.1}°C (target {:.1}°C)",
+        status.nozzle_temperature, status.nozzle_target_temperature
+    );
+    let _ = writeln!(
+        out,
+        "Bed:       {:.1}°C (target {:.1}°C)",
+        status.heated_bed_temperature, status.heated_bed_target_temperature
+    );
+    let _ = writeln!(out, "\nReported at {}", event.timestamp.to_rfc3339());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    use super::*;
+    use crate::{events::create_event_channel, gcode::Thumbnail, status::PrinterStatus};
+
+    /// Accepts every mail on `listener` and hands over what came after DATA
+    async fn smtp_sink(listener: TcpListener, mails: mpsc::UnboundedSender<String>) {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else {
+                return;
+            };
+            let mails = mails.clone();
+            tokio::spawn(async move {
+                let (reader, mut writer) = stream.into_split();
+                let mut lines = BufReader::new(reader).lines();
+                writer.write_all(b"220 sink ESMTP\r\n").await?;
+                while let Some(line) = lines.next_line().await? {
+                    let command = line.to_ascii_uppercase();
+                    let reply: &[u8] = if command.starts_with("EHLO") {
+                        b"250-sink\r\n250 8BITMIME\r\n"
+                    } else if command == "DATA" {
+                        writer.write_all(b"354 go ahead\r\n").await?;
+                        let mut mail = String::new();
+                        while let Some(line) = lines.next_line().await? {
+                            if line == "." {
+                                break;
+                            }
+                            mail.push_str(&line);
+                            mail.push('\n');
+                        }
+                        let _ = mails.send(mail);
+                        b"250 queued\r\n"
+                    } else if command == "QUIT" {
+                        writer.write_all(b"221 bye\r\n").await?;
+                        break;
+                    } else {
+                        b"250 ok\r\n"
+                    };
+                    writer.write_all(reply).await?;
+                }
+                anyhow::Ok(())
+            });
+        }
+    }
+
+    fn printing(file_name: &str) -> PrinterStatus {
+        PrinterStatus {
+            status: "RUNNING".to_string(),
+            file_name: file_name.to_string(),
+            progress: 1.0,
+            elapsed_time: 3720.0,
+            estimated_time: 3600.0,
+            nozzle_temperature: 205.0,
+            nozzle_target_temperature: 210.0,
+            heated_bed_temperature: 60.0,
+            heated_bed_target_temperature: 60.0,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn builds_a_transport_for_every_tls_mode() {
+        for tls in [SmtpTls::None, SmtpTls::StartTls, SmtpTls::Tls] {
+            let config = SmtpConfig {
+                host: "smtp.example.com",
+                port: 587,
+                tls,
+                username: Some("printer"),
+                password: Some("secret"),
+                from: "Snapmaker <printer@example.com>",
+                to: &["team@example.com"],
+            };
+            assert!(build_transport(&config).is_ok(), "{tls:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn mails_a_finished_print_with_its_thumbnail() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = SmtpConfig {
+            host: "127.0.0.1",
+            port: listener.local_addr().unwrap().port(),
+            tls: SmtpTls::None,
+            username: None,
+            password: None,
+            from: "Snapmaker <printer@example.com>",
+            to: &["team@example.com"],
+        };
+        let (sender, mut mails) = mpsc::unbounded_channel();
+        tokio::spawn(smtp_sink(listener, sender));
+        let last_upload = Arc::new(Mutex::new(Some(UploadedFile {
+            file_name: "bracket.gcode".to_string(),
+            thumbnail: Some(Thumbnail {
+                data: b"not really a png".to_vec(),
+                mime: "image/png",
+            }),
+        })));
+        let (event_sender, events) = create_event_channel();
+        tokio::spawn(run(config, events, last_upload));
+
+        let event = |kind, message: &str| {
+            PrinterEvent::new(kind, message.to_string(), printing("bracket.gcode"))
+        };
+        event_sender.send(event(EventKind::PrintStarted, "Started")).unwrap();
+        // pauses don't mail anyone
+        event_sender.send(event(EventKind::PrintPaused, "Paused")).unwrap();
+        event_sender.send(event(EventKind::PrintFinished, "Finished bracket.gcode")).unwrap();
+
+        let mail = tokio::time::timeout(Duration::from_secs(5), mails.recv())
+            .await
+            .expect("no mail arrived")
+            .unwrap();
+        assert!(mail.contains("Subject: snapmaker: Print finished"), "{mail}");
+        assert!(mail.contains("To: team@example.com"), "{mail}");
+        assert!(mail.contains("File:      bracket.gcode"), "{mail}");
+        assert!(mail.contains("Duration:  1h 2m (estimated 1h 0m, +2m 0s)"), "{mail}");
+        // quoted-printable, the ° is two bytes of UTF-8
+        assert!(mail.contains("Nozzle:    205.0=C2=B0C (target 210.0=C2=B0C)"), "{mail}");
+        assert!(mail.contains("Wall time:"), "{mail}");
+        assert!(mail.contains("Content-Type: image/png"), "{mail}");
+        assert!(mail.contains("filename=\"bracket.png\""), "{mail}");
+        assert!(mails.try_recv().is_err());
+    }
+}
@@ -0,0 +1,383 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::{config::STALL_TIMEOUT, status::PrinterStatus};
+
+// keep-alive failures in a row before the printer counts as disconnected
+pub(crate) const DISCONNECT_AFTER_FAILURES: u32 = 3;
+
+/// Set once a stop went out through the proxy, the job that ends next was cancelled
+static STOP_SENT: AtomicBool = AtomicBool::new(false);
+
+/// Tells the detector the running job was stopped on purpose, by a client or the
+/// thermal watchdog
+pub fn stop_sent() {
+    STOP_SENT.store(true, Ordering::SeqCst);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    PrintStarted,
+    PrintPaused,
+    PrintResumed,
+    PrintFinished,
+    PrintCancelled,
+    PrintStalled,
+    PrinterDisconnected,
+    PrinterReconnected,
+    /// Raised by the thermal watchdog
+    TemperatureAnomaly,
+}
+
+impl EventKind {
+    pub fn title(&self) -> &'static str {
+        match self {
+            EventKind::PrintStarted => "Print started",
+            EventKind::PrintPaused => "Print paused",
+            EventKind::PrintResumed => "Print resumed",
+            EventKind::PrintFinished => "Print finished",
+            EventKind::PrintCancelled => "Print cancelled",
+            EventKind::PrintStalled => "Print stalled",
+            EventKind::PrinterDisconnected => "Printer disconnected",
+            EventKind::PrinterReconnected => "Printer reconnected",
+            EventKind::TemperatureAnomaly => "Temperature anomaly",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PrinterEvent {
+    pub kind: EventKind,
+    pub timestamp: DateTime<Utc>,
+    pub message: String,
+    /// The snapshot the event is about, for a finished print the last one while printing
+    pub status: PrinterStatus,
+}
+
+impl PrinterEvent {
+    pub fn new(kind: EventKind, message: String, status: PrinterStatus) -> Self {
+        Self {
+            kind,
+            timestamp: Utc::now(),
+            message,
+            status,
+        }
+    }
+}
+
+pub fn create_event_channel() -> (
+    broadcast::Sender<PrinterEvent>,
+    broadcast::Receiver<PrinterEvent>,
+) {
+    broadcast::channel(64)
+}
+
+fn is_active(state: &str) -> bool {
+    state == "RUNNING" || state == "PAUSED"
+}
+
+pub fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.max(0.0) as u64;
+    match seconds {
+        0..60 => format!("{seconds}s"),
+        60..3600 => format!("{}m {}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {}m", seconds / 3600, (seconds % 3600) / 60),
+    }
+}
+
+/// Turns consecutive keep-alive results into lifecycle events
+#[derive(Default)]
+pub struct EventDetector {
+    previous: Option<PrinterStatus>,
+    failures: u32,
+    disconnected: bool,
+    stall: StallDetector,
+}
+
+impl EventDetector {
+    pub fn observe(&mut self, status: &PrinterStatus) -> Vec<PrinterEvent> {
+        let mut events = Vec::new();
+        self.failures = 0;
+        if self.disconnected {
+            self.disconnected = false;
+            events.push(PrinterEvent::new(
+                EventKind::PrinterReconnected,
+                "Printer is reachable again".to_string(),
+                status.clone(),
+            ));
+        }
+
+        if let Some(previous) = self.previous.take() {
+            // a stop only says something about the job it was sent to
+            let stopped = (is_active(&previous.status) != is_active(&status.status))
+                && STOP_SENT.swap(false, Ordering::SeqCst);
+            events.extend(state_events(&previous, status, stopped));
+        }
+        events.extend(self.stall.observe(status, Instant::now()));
+        self.previous = Some(status.clone());
+        events
+    }
+
+    /// When the running print stopped making progress, if it did
+    pub fn stalled_since(&self) -> Option<DateTime<Utc>> {
+        self.stall.stalled_since
+    }
+
+    pub fn observe_failure(&mut self, error: &str) -> Vec<PrinterEvent> {
+        self.failures += 1;
+        if self.disconnected || self.failures < DISCONNECT_AFTER_FAILURES {
+            return Vec::new();
+        }
+        self.disconnected = true;
+        let status = self.previous.clone().unwrap_or_default();
+        vec![PrinterEvent::new(
+            EventKind::PrinterDisconnected,
+            format!("Lost connection to the printer: {error}"),
+            status,
+        )]
+    }
+}
+
+/// Notices a `RUNNING` print whose progress and position stopped changing
+#[derive(Default)]
+pub struct StallDetector {
+    /// Last snapshot that differed from the one before, with the print timer at that point
+    last_change: Option<(Snapshot, f64, Instant)>,
+    stalled_since: Option<DateTime<Utc>>,
+}
+
+#[derive(PartialEq)]
+struct Snapshot {
+    progress: f64,
+    x: f64,
+    y: f64,
+    z: f64,
+}
+
+impl StallDetector {
+    pub fn observe(&mut self, status: &PrinterStatus, now: Instant) -> Option<PrinterEvent> {
+        if status.status != "RUNNING" {
+            self.last_change = None;
+            self.stalled_since = None;
+            return None;
+        }
+        let snapshot = Snapshot {
+            progress: status.progress,
+            x: status.x,
+            y: status.y,
+            z: status.z,
+        };
+        let (elapsed_at_change, unchanged_for) = match &self.last_change {
+            Some((last, elapsed, at)) if *last == snapshot => (*elapsed, now.duration_since(*at)),
+            _ => {
+                self.last_change = Some((snapshot, status.elapsed_time, now));
+                self.stalled_since = None;
+                return None;
+            }
+        };
+        if self.stalled_since.is_some() || unchanged_for < STALL_TIMEOUT {
+            return None;
+        }
+        self.stalled_since = Some(
+            Utc::now() - chrono::Duration::from_std(unchanged_for).unwrap_or_default(),
+        );
+        // a print timer that keeps counting means the firmware is alive and waiting on something
+        let hint = if status.elapsed_time > elapsed_at_change {
+            "check the touchscreen for a prompt such as filament runout"
+        } else {
+            "the firmware may have hung"
+        };
+        Some(PrinterEvent::new(
+            EventKind::PrintStalled,
+            format!(
+                "{} made no progress for {} at {:.1}%, {hint}",
+                status.file_name,
+                format_duration(unchanged_for.as_secs_f64()),
+                status.progress * 100.0
+            ),
+            status.clone(),
+        ))
+    }
+}
+
+/// `stopped` when a stop went out through the proxy since the last snapshot
+fn state_events(
+    previous: &PrinterStatus,
+    status: &PrinterStatus,
+    stopped: bool,
+) -> Vec<PrinterEvent> {
+    let (before, after) = (previous.status.as_str(), status.status.as_str());
+    if before == after {
+        return Vec::new();
+    }
+    let file = &previous.file_name;
+    let event = match (before, after) {
+        (_, "RUNNING") if !is_active(before) => PrinterEvent::new(
+            EventKind::PrintStarted,
+            format!("Started printing {}", status.file_name),
+            status.clone(),
+        ),
+        ("RUNNING", "PAUSED") => PrinterEvent::new(
+            EventKind::PrintPaused,
+            format!("Paused {file} at {:.1}%", previous.progress * 100.0),
+            status.clone(),
+        ),
+        ("PAUSED", "RUNNING") => PrinterEvent::new(
+            EventKind::PrintResumed,
+            format!("Resumed {file}"),
+            status.clone(),
+        ),
+        // once the job is gone the printer resets its job fields, so report the last active snapshot
+        _ if is_active(before) && !is_active(after) => {
+            // the state the printer ends in says it best, then whether we stopped it,
+            // progress is a guess: firmware that goes straight back to IDLE may
+            // never report the last percent, or report it for a job stopped at the end
+            let finished = match after {
+                "FINISHED" => true,
+                "STOPPED" => false,
+                _ => !stopped && previous.progress >= 0.99,
+            };
+            if finished {
+                PrinterEvent::new(
+                    EventKind::PrintFinished,
+                    format!(
+                        "Finished {file} after {}",
+                        format_duration(previous.elapsed_time)
+                    ),
+                    previous.clone(),
+                )
+            } else {
+                PrinterEvent::new(
+                    EventKind::PrintCancelled,
+                    format!(
+                        "Cancelled {file} at {:.1}% after {}",
+                        previous.progress * 100.0,
+                        format_duration(previous.elapsed_time)
+                    ),
+                    previous.clone(),
+                )
+            }
+        }
+        _ => return Vec::new(),
+    };
+    vec![event]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn status(state: &str, progress: f64) -> PrinterStatus {
+        PrinterStatus {
+            status: state.to_string(),
+            file_name: "benchy.gcode".to_string(),
+            progress,
+            elapsed_time: 3600.0,
+            ..Default::default()
+        }
+    }
+
+    fn ending(before: &PrinterStatus, after: &str, stopped: bool) -> EventKind {
+        let events = state_events(before, &status(after, 0.0), stopped);
+        assert_eq!(events.len(), 1);
+        events[0].kind
+    }
+
+    #[test]
+    fn final_state_decides() {
+        let early = status("RUNNING", 0.4);
+        let late = status("RUNNING", 0.995);
+        assert_eq!(ending(&early, "FINISHED", false), EventKind::PrintFinished);
+        assert_eq!(ending(&late, "STOPPED", false), EventKind::PrintCancelled);
+        assert_eq!(ending(&status("PAUSED", 0.99), "STOPPED", false), EventKind::PrintCancelled);
+    }
+
+    #[test]
+    fn stop_through_the_proxy_is_a_cancel() {
+        let late = status("RUNNING", 0.995);
+        assert_eq!(ending(&late, "IDLE", true), EventKind::PrintCancelled);
+        // the printer saying it finished wins over a stop that came too late
+        assert_eq!(ending(&late, "FINISHED", true), EventKind::PrintFinished);
+    }
+
+    #[test]
+    fn progress_is_the_fallback() {
+        assert_eq!(ending(&status("RUNNING", 0.995), "IDLE", false), EventKind::PrintFinished);
+        assert_eq!(ending(&status("RUNNING", 0.5), "IDLE", false), EventKind::PrintCancelled);
+    }
+
+    #[test]
+    fn reports_the_last_active_snapshot() {
+        let events = state_events(&status("RUNNING", 0.5), &status("IDLE", 0.0), true);
+        assert_eq!(events[0].message, "Cancelled benchy.gcode at 50.0% after 1h 0m");
+        assert_eq!(events[0].status.progress, 0.5);
+    }
+
+    /// Feeds `steps` of (minutes, status) and returns the minutes a stall was reported at
+    fn stalls(steps: &[(u64, PrinterStatus)]) -> Vec<u64> {
+        let start = Instant::now();
+        let mut detector = StallDetector::default();
+        steps
+            .iter()
+            .filter(|(minutes, status)| {
+                let now = start + Duration::from_secs(minutes * 60);
+                detector.observe(status, now).is_some()
+            })
+            .map(|(minutes, _)| *minutes)
+            .collect()
+    }
+
+    #[test]
+    fn unchanged_print_stalls_once() {
+        let steps: Vec<_> = (0..=30).map(|x| (x, status("RUNNING", 0.4))).collect();
+        assert_eq!(stalls(&steps), [STALL_TIMEOUT.as_secs() / 60]);
+
+        let mut detector = StallDetector::default();
+        let start = Instant::now();
+        let mut stuck = status("RUNNING", 0.4);
+        detector.observe(&stuck, start);
+        // the print timer kept counting, the firmware waits on something
+        stuck.elapsed_time += 600.0;
+        let event = detector.observe(&stuck, start + STALL_TIMEOUT).unwrap();
+        assert_eq!(event.kind, EventKind::PrintStalled);
+        assert!(event.message.contains("filament runout"), "{}", event.message);
+        assert!(detector.stalled_since.is_some());
+    }
+
+    #[test]
+    fn movement_resets_the_stall() {
+        let moved = |z| PrinterStatus {
+            z,
+            ..status("RUNNING", 0.4)
+        };
+        // the head keeps moving every 8 minutes, then stops at 24
+        let mut steps: Vec<_> = (0..=40).map(|x| (x, moved((x / 8).min(3) as f64))).collect();
+        assert_eq!(stalls(&steps), [34]);
+
+        // so does progress, and a stall is reported again after it stops anew
+        steps = (0..=40).map(|x| (x, status("RUNNING", if x < 15 { 0.4 } else { 0.5 }))).collect();
+        assert_eq!(stalls(&steps), [10, 25]);
+    }
+
+    #[test]
+    fn only_running_prints_stall() {
+        for state in ["IDLE", "PAUSED", "FINISHED", "STOPPED"] {
+            let steps: Vec<_> = (0..=30).map(|x| (x, status(state, 0.4))).collect();
+            assert!(stalls(&steps).is_empty(), "{state}");
+        }
+        // a pause starts the wait over
+        let steps: Vec<_> = (0..=30)
+            .map(|x| (x, status(if x == 8 { "PAUSED" } else { "RUNNING" }, 0.4)))
+            .collect();
+        assert_eq!(stalls(&steps), [19]);
+    }
+}
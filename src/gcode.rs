@@ -0,0 +1,175 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+
+// slicers put thumbnails into the header, don't read whole prints looking for one
+const HEADER_SCAN_LIMIT: usize = 4 * 1024 * 1024;
+
+/// A preview image embedded in a G-code file by the slicer
+#[derive(Debug, Clone)]
+pub struct Thumbnail {
+    pub data: Vec<u8>,
+    pub mime: &'static str,
+}
+
+impl Thumbnail {
+    pub fn extension(&self) -> &'static str {
+        match self.mime {
+            "image/jpeg" => "jpg",
+            _ => "png",
+        }
+    }
+}
+
+/// What we still know about the last file sent to the printer
+#[derive(Debug, Clone)]
+pub struct UploadedFile {
+    pub file_name: String,
+    pub thumbnail: Option<Thumbnail>,
+}
+
+/// Largest thumbnail in the header, understands the PrusaSlicer/Cura
+/// `; thumbnail begin` blocks and Luban's `;thumbnail: data:` line
+pub fn extract_thumbnail(path: &Path) -> std::io::Result<Option<Thumbnail>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut line = Vec::new();
+    let mut read = 0;
+    let mut block: Option<(&'static str, String)> = None;
+    let mut best: Option<Thumbnail> = None;
+
+    while read < HEADER_SCAN_LIMIT {
+        line.clear();
+        let n = reader.read_until(b'\n', &mut line)?;
+        if n == 0 {
+            break;
+        }
+        read += n;
+        let text = String::from_utf8_lossy(&line);
+        let Some(comment) = text.trim().strip_prefix(';') else {
+            continue;
+        };
+        let comment = comment.trim();
+
+        if let Some((mime, data)) = &mut block {
+            if comment.starts_with("thumbnail") && comment.contains(" end") {
+                let thumbnail = decode(mime, data);
+                best = larger(best, thumbnail);
+                block = None;
+            } else {
+                data.push_str(comment);
+            }
+        } else if let Some(uri) = comment.strip_prefix("thumbnail: data:") {
+            if let Some((mime, data)) = uri.split_once(";base64,") {
+                let mime = match mime {
+                    "image/jpeg" | "image/jpg" => "image/jpeg",
+                    _ => "image/png",
+                };
+                best = larger(best, decode(mime, data));
+            }
+        } else if let Some(kind) = block_start(comment) {
+            block = Some((kind, String::new()));
+        }
+    }
+    Ok(best)
+}
+
+fn block_start(comment: &str) -> Option<&'static str> {
+    let (tag, rest) = comment.split_once(' ')?;
+    if !rest.starts_with("begin") {
+        return None;
+    }
+    match tag {
+        "thumbnail" | "thumbnail_PNG" => Some("image/png"),
+        "thumbnail_JPG" => Some("image/jpeg"),
+        _ => None,
+    }
+}
+
+fn decode(mime: &'static str, data: &str) -> Option<Thumbnail> {
+    STANDARD
+        .decode(data.trim())
+        .ok()
+        .map(|data| Thumbnail { data, mime })
+}
+
+fn larger(best: Option<Thumbnail>, candidate: Option<Thumbnail>) -> Option<Thumbnail> {
+    match (best, candidate) {
+        (Some(best), Some(candidate)) if candidate.data.len() > best.data.len() => Some(candidate),
+        (Some(best), _) => Some(best),
+        (None, candidate) => candidate,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+    fn thumbnail(contents: &[u8]) -> Option<Thumbnail> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(contents).unwrap();
+        extract_thumbnail(file.path()).unwrap()
+    }
+
+    #[test]
+    fn reads_a_thumbnail_block() {
+        let gcode = b"; generated by PrusaSlicer\n\
+            ;\n\
+            ; thumbnail begin 16x16 12\n\
+            ; iVBORw0K\n\
+            ; Ggo=\n\
+            ; thumbnail end\n\
+            G28\n";
+        let found = thumbnail(gcode).unwrap();
+        assert_eq!(found.data, PNG_SIGNATURE);
+        assert_eq!(found.extension(), "png");
+    }
+
+    #[test]
+    fn prefers_the_largest_thumbnail() {
+        let gcode = b"; thumbnail begin 16x16 12\n\
+            ; iVBORw0KGgo=\n\
+            ; thumbnail end\n\
+            ; thumbnail_JPG begin 300x300 12\n\
+            ; /9j/4AAQSkZJRgAB\n\
+            ; thumbnail_JPG end\n\
+            ;thumbnail: data:image/png;base64,iVBO\n";
+        let found = thumbnail(gcode).unwrap();
+        assert_eq!(found.mime, "image/jpeg");
+        assert_eq!(found.data, b"\xff\xd8\xff\xe0\x00\x10JFIF\x00\x01");
+    }
+
+    #[test]
+    fn reads_a_data_uri_line() {
+        let found = thumbnail(b";thumbnail: data:image/png;base64,iVBORw0KGgo=\nG28\n").unwrap();
+        assert_eq!(found.data, PNG_SIGNATURE);
+    }
+
+    #[test]
+    fn no_thumbnail() {
+        assert!(thumbnail(b"; generated by Cura\nG28\nG1 X10 Y10\n").is_none());
+        assert!(thumbnail(b"").is_none());
+    }
+
+    #[test]
+    fn truncated_block_is_ignored() {
+        // the file ends before the block does
+        assert!(thumbnail(b"; thumbnail begin 16x16 12\n; iVBORw0K").is_none());
+        // or the block is cut short and no longer decodes
+        assert!(thumbnail(b"; thumbnail begin 16x16 12\n; iVBORw0KG\n; thumbnail end\n").is_none());
+    }
+
+    #[test]
+    fn thumbnail_past_the_header_is_not_looked_for() {
+        let mut gcode = b"; settings\n".repeat(HEADER_SCAN_LIMIT / 10);
+        gcode.extend_from_slice(b"; thumbnail begin 16x16 12\n; iVBORw0KGgo=\n; thumbnail end\n");
+        assert!(thumbnail(&gcode).is_none());
+    }
+}
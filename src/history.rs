@@ -0,0 +1,106 @@
+use std::{collections::VecDeque, fs, path::Path, sync::Mutex, time::Duration};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::status::PrinterStatus;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemperatureSample {
+    pub timestamp: DateTime<Utc>,
+    pub nozzle: f64,
+    pub nozzle_target: f64,
+    pub bed: f64,
+    pub bed_target: f64,
+    pub enclosure_fan: u8,
+}
+
+impl TemperatureSample {
+    pub fn from_status(status: &PrinterStatus) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            nozzle: status.nozzle_temperature,
+            nozzle_target: status.nozzle_target_temperature,
+            bed: status.heated_bed_temperature,
+            bed_target: status.heated_bed_target_temperature,
+            enclosure_fan: status.enclosure.fan,
+        }
+    }
+}
+
+/// Temperatures of the last `window`, appended by the keep-alive loop
+pub struct TemperatureHistory {
+    samples: Mutex<VecDeque<TemperatureSample>>,
+    window: Duration,
+    capacity: usize,
+}
+
+impl TemperatureHistory {
+    pub fn new(window: Duration) -> Self {
+        // room for two samples a second, in case polling speeds up
+        let capacity = (window.as_secs() as usize).max(1) * 2;
+        Self {
+            samples: Mutex::new(VecDeque::new()),
+            window,
+            capacity,
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    pub fn push(&self, sample: TemperatureSample) {
+        let mut samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+        let oldest = sample.timestamp
+            - chrono::Duration::from_std(self.window).unwrap_or(chrono::TimeDelta::MAX);
+        while samples
+            .front()
+            .is_some_and(|x| x.timestamp < oldest || samples.len() >= self.capacity)
+        {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    /// Picks up the samples saved at the last shutdown, those older than the
+    /// window are dropped
+    pub fn restore(&self, path: &Path) -> anyhow::Result<usize> {
+        let content = match fs::read_to_string(path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let samples: Vec<TemperatureSample> = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid temperature history {}", path.display()))?;
+        let oldest =
+            Utc::now() - chrono::Duration::from_std(self.window).unwrap_or(chrono::TimeDelta::MAX);
+        let mut restored = 0;
+        for sample in samples.into_iter().filter(|x| x.timestamp >= oldest) {
+            self.push(sample);
+            restored += 1;
+        }
+        Ok(restored)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let samples = self.samples(None);
+        // write next to the file and rename, a crash never leaves half a file behind
+        let temporary = path.with_extension("json.tmp");
+        fs::write(&temporary, serde_json::to_string(&samples)?)
+            .with_context(|| format!("Failed to write {}", temporary.display()))?;
+        fs::rename(&temporary, path).with_context(|| format!("Failed to replace {}", path.display()))
+    }
+
+    /// Oldest first, only samples taken after `since` (unix milliseconds, what
+    /// browsers parse timestamps to) when given
+    pub fn samples(&self, since: Option<i64>) -> Vec<TemperatureSample> {
+        let samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+        samples
+            .iter()
+            .filter(|x| since.is_none_or(|since| x.timestamp.timestamp_millis() > since))
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,41 @@
+use super::AppState;
+use actix_web::{HttpResponse, Responder, get, web};
+use serde::Deserialize;
+use tera::Context;
+
+#[derive(Debug, Deserialize)]
+struct AuditQuery {
+    limit: Option<usize>,
+}
+
+#[get("/api/audit")]
+pub async fn get_audit(data: web::Data<AppState>, query: web::Query<AuditQuery>) -> impl Responder {
+    match data.audit.recent(query.limit.unwrap_or(100).min(1000)) {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(e) => {
+            log::error!("Failed to read audit log: {:?}", e);
+            HttpResponse::InternalServerError().body(format!("Failed to read audit log: {}", e))
+        }
+    }
+}
+
+#[get("/audit")]
+pub async fn get_rendered_audit(data: web::Data<AppState>) -> impl Responder {
+    let entries = match data.audit.recent(500) {
+        Ok(x) => x,
+        Err(e) => {
+            log::error!("Failed to read audit log: {:?}", e);
+            return HttpResponse::InternalServerError().body("Failed to read audit log");
+        }
+    };
+    let mut context = Context::new();
+    context.insert("entries", &entries);
+
+    match data.tera.render("audit.html.tera", &context) {
+        Ok(html) => HttpResponse::Ok().content_type("text/html").body(html),
+        Err(e) => {
+            log::error!("Failed to render audit template: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to render template")
+        }
+    }
+}
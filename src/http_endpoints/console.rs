@@ -0,0 +1,95 @@
+use super::AppState;
+use crate::console::Direction;
+use crate::interlock::{Command, Confirmation, is_print_safe};
+use actix_web::{Either, HttpRequest, HttpResponse, Responder, get, post, web};
+use serde::Deserialize;
+use serde_json::json;
+use tera::Context;
+
+/// OctoPrint's `/api/printer/command` body, either one `command` or a list of `commands`
+#[derive(Debug, Deserialize)]
+struct CommandRequest {
+    command: Option<String>,
+    #[serde(default)]
+    commands: Vec<String>,
+}
+
+/// The web terminal posts a single (possibly multi-line) `command` field
+#[derive(Debug, Deserialize)]
+struct CommandForm {
+    command: String,
+}
+
+/// One G-code per line, comments and blank lines dropped
+fn split_commands(commands: impl IntoIterator<Item = String>) -> Vec<String> {
+    commands
+        .into_iter()
+        .flat_map(|x| {
+            x.lines()
+                .map(|line| line.split(';').next().unwrap_or_default().trim().to_string())
+                .collect::<Vec<_>>()
+        })
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+#[post("/api/printer/command")]
+pub async fn send_printer_command(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    body: Either<web::Json<CommandRequest>, web::Form<CommandForm>>,
+) -> impl Responder {
+    let commands = match body {
+        Either::Left(web::Json(x)) => split_commands(x.command.into_iter().chain(x.commands)),
+        Either::Right(web::Form(x)) => split_commands([x.command]),
+    };
+    if commands.is_empty() {
+        return HttpResponse::BadRequest().body("No command given");
+    }
+    let parameters = json!({ "commands": commands });
+    let print_safe = commands.iter().all(|x| is_print_safe(x));
+    if let Err(response) = data.check_command(
+        &req,
+        "gcode",
+        &parameters,
+        Command::Gcode { print_safe },
+        &Confirmation::default(),
+    ) {
+        data.console.push(Direction::Error, &format!("Refused: {}", commands.join(" | ")));
+        return response;
+    }
+
+    let result = data.run_gcode(&commands).await;
+    data.audit.record(&req, "gcode", parameters, &result);
+    match result {
+        Ok(responses) => {
+            let responses: Vec<_> = responses
+                .into_iter()
+                .map(|(command, response)| json!({ "command": command, "response": response }))
+                .collect();
+            HttpResponse::Ok().json(json!({ "responses": responses }))
+        }
+        Err(e) => {
+            log::error!("Failed to execute G-code: {:?}", e);
+            HttpResponse::InternalServerError().body(format!("Failed to execute G-code: {}", e))
+        }
+    }
+}
+
+#[get("/api/console")]
+pub async fn get_console(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(data.console.lines())
+}
+
+#[get("/render/console")]
+pub async fn get_rendered_console(data: web::Data<AppState>) -> impl Responder {
+    let mut context = Context::new();
+    context.insert("lines", &data.console.lines());
+    match data.tera.render("console.html.tera", &context) {
+        Ok(html) => HttpResponse::Ok().content_type("text/html").body(html),
+        Err(e) => {
+            log::error!("Failed to render console template: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to render template")
+        }
+    }
+}
@@ -1,9 +1,25 @@
 use super::AppState;
-use actix_web::{HttpResponse, Responder, post, web};
+use crate::interlock::{Command, Confirmation};
+use actix_web::{HttpRequest, HttpResponse, Responder, post, web};
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Debug, Deserialize)]
+struct StopQuery {
+    confirm: Option<bool>,
+    token: Option<String>,
+}
 
 #[post("/api/pause_print")]
-pub async fn pause_print(data: web::Data<AppState>) -> impl Responder {
-    match crate::snapmaker_client::pause_print(&data.snapmaker_token).await {
+pub async fn pause_print(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    if let Err(response) =
+        data.check_command(&req, "pause_print", &json!({}), Command::Pause, &Confirmation::default())
+    {
+        return response;
+    }
+    let result = crate::snapmaker_client::pause_print(&data.token()).await;
+    data.audit.record(&req, "pause_print", json!({}), &result);
+    match result {
         Ok(_) => HttpResponse::Ok().body("Print paused successfully"),
         Err(e) => {
             log::error!("Failed to pause print: {:?}", e);
@@ -13,8 +29,22 @@ pub async fn pause_print(data: web::Data<AppState>) -> impl Responder {
 }
 
 #[post("/api/stop_print")]
-pub async fn stop_print(data: web::Data<AppState>) -> impl Responder {
-    match crate::snapmaker_client::stop_print(&data.snapmaker_token).await {
+pub async fn stop_print(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    query: web::Query<StopQuery>,
+) -> impl Responder {
+    let confirmation = Confirmation {
+        confirm: query.confirm.unwrap_or(false),
+        token: query.token.clone(),
+    };
+    let parameters = json!({ "confirm": confirmation.confirm, "token": confirmation.token.is_some() });
+    if let Err(response) = data.check_command(&req, "stop_print", &parameters, Command::Stop, &confirmation) {
+        return response;
+    }
+    let result = crate::snapmaker_client::stop_print(&data.token()).await;
+    data.audit.record(&req, "stop_print", parameters, &result);
+    match result {
         Ok(_) => HttpResponse::Ok().body("Print stopped successfully"),
         Err(e) => {
             log::error!("Failed to stop print: {:?}", e);
@@ -24,8 +54,15 @@ pub async fn stop_print(data: web::Data<AppState>) -> impl Responder {
 }
 
 #[post("/api/resume_print")]
-pub async fn resume_print(data: web::Data<AppState>) -> impl Responder {
-    match crate::snapmaker_client::resume_print(&data.snapmaker_token).await {
+pub async fn resume_print(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    if let Err(response) =
+        data.check_command(&req, "resume_print", &json!({}), Command::Resume, &Confirmation::default())
+    {
+        return response;
+    }
+    let result = crate::snapmaker_client::resume_print(&data.token()).await;
+    data.audit.record(&req, "resume_print", json!({}), &result);
+    match result {
         Ok(_) => HttpResponse::Ok().body("Print resumed successfully"),
         Err(e) => {
             log::error!("Failed to resume print: {:?}", e);
@@ -0,0 +1,48 @@
+use super::AppState;
+use crate::config::DISCOVER_PRINTER;
+use crate::discovery::{discovery, endpoint};
+use actix_web::{HttpResponse, Responder, get, post, web};
+use serde_json::{Value, json};
+use tera::Context;
+
+fn discovered() -> Value {
+    json!({
+        "printer": DISCOVER_PRINTER,
+        "endpoint": endpoint(),
+        "last_scan": discovery().last_scan(),
+        "printers": discovery().printers(),
+    })
+}
+
+#[get("/api/discovery")]
+pub async fn get_discovery() -> impl Responder {
+    HttpResponse::Ok().json(discovered())
+}
+
+#[post("/api/discovery/scan")]
+pub async fn scan_for_printers() -> impl Responder {
+    match discovery().scan().await {
+        Ok(_) => HttpResponse::Ok().json(discovered()),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Discovery failed: {e}")),
+    }
+}
+
+#[get("/render/discovery")]
+pub async fn get_rendered_discovery(data: web::Data<AppState>) -> impl Responder {
+    // the first look at the list shouldn't come up empty
+    if discovery().last_scan().is_none()
+        && let Err(e) = discovery().scan().await
+    {
+        log::warn!("Printer discovery failed: {e}");
+    }
+    let mut context = Context::new();
+    context.insert("discovery", &discovered());
+    context.insert("read_only", &data.read_only);
+    match data.tera.render("discovery.html.tera", &context) {
+        Ok(html) => HttpResponse::Ok().content_type("text/html").body(html),
+        Err(e) => {
+            log::error!("Failed to render discovery template: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to render template")
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use super::AppState;
-use actix_web::{HttpResponse, Responder, post, web};
+use crate::interlock::{Command, Confirmation};
+use actix_web::{HttpRequest, HttpResponse, Responder, post, web};
 use serde::Deserialize;
+use serde_json::json;
 
 #[derive(Debug, Deserialize)]
 struct EnclosureLightRequest {
@@ -14,10 +16,23 @@ struct EnclosureFanRequest {
 
 #[post("/api/enclosure/light")]
 pub async fn set_enclosure_light(
+    req: HttpRequest,
     data: web::Data<AppState>,
     request: web::Form<EnclosureLightRequest>,
 ) -> impl Responder {
-    match crate::snapmaker_client::set_enclosure_light(&data.snapmaker_token, request.value as u8).await {
+    let parameters = json!({ "value": request.value });
+    if let Err(response) = data.check_command(
+        &req,
+        "set_enclosure_light",
+        &parameters,
+        Command::Enclosure,
+        &Confirmation::default(),
+    ) {
+        return response;
+    }
+    let result = crate::snapmaker_client::set_enclosure_light(&data.token(), request.value as u8).await;
+    data.audit.record(&req, "set_enclosure_light", parameters, &result);
+    match result {
         Ok(_) => HttpResponse::Ok().body(request.value.to_string()),
         Err(e) => {
             log::error!("Failed to set enclosure light: {:?}", e);
@@ -28,10 +43,23 @@ pub async fn set_enclosure_light(
 
 #[post("/api/enclosure/fan")]
 pub async fn set_enclosure_fan(
+    req: HttpRequest,
     data: web::Data<AppState>,
     request: web::Form<EnclosureFanRequest>,
 ) -> impl Responder {
-    match crate::snapmaker_client::set_enclosure_fan(&data.snapmaker_token, request.value as u8).await {
+    let parameters = json!({ "value": request.value });
+    if let Err(response) = data.check_command(
+        &req,
+        "set_enclosure_fan",
+        &parameters,
+        Command::Enclosure,
+        &Confirmation::default(),
+    ) {
+        return response;
+    }
+    let result = crate::snapmaker_client::set_enclosure_fan(&data.token(), request.value as u8).await;
+    data.audit.record(&req, "set_enclosure_fan", parameters, &result);
+    match result {
         Ok(_) => HttpResponse::Ok().body(request.value.to_string()),
         Err(e) => {
             log::error!("Failed to set enclosure fan: {:?}", e);
@@ -0,0 +1,123 @@
+use super::AppState;
+use crate::config::{
+    COMMAND_BURST, COMMAND_RATE_PER_MINUTE, DISCOVER_PRINTER, ENCLOSURE_POLL_INTERVAL,
+    IDLE_POLL_INTERVAL, MACHINE, MAX_PRINTER_REQUESTS, MDNS_ADVERTISE, MDNS_NAME, MQTT_BROKER,
+    MQTT_PASSWORD, MQTT_USERNAME, OBSERVER_ADDRESS, POLL_INTERVAL, PRINTER_CONNECT_TIMEOUT,
+    PRINTER_NAME, PRINTER_REQUEST_TIMEOUT, PRINTER_UPLOAD_TIMEOUT, READY_MAX_POLL_AGE, READ_ONLY,
+    RECONNECT_MAX_BACKOFF, SERVE_ADDRESS, SMTP, SNAPMAKER_ENDPOINT, STALL_TIMEOUT, THERMAL_WATCHDOG,
+    TLS_ADDRESS, TOKEN_KEY_FILE, WEBHOOKS,
+};
+use crate::discovery::endpoint;
+use crate::metrics::metrics;
+use crate::snapmaker_client::printer_info;
+use crate::token_store;
+use crate::webhooks::target_name;
+use actix_web::{HttpResponse, Responder, get, web};
+use chrono::Utc;
+use serde_json::{Value, json};
+
+/// The process is up and answering
+#[get("/healthz")]
+pub async fn get_health() -> impl Responder {
+    HttpResponse::Ok().body("ok")
+}
+
+/// We have a token and the last poll is recent enough
+#[get("/readyz")]
+pub async fn get_readiness(data: web::Data<AppState>) -> impl Responder {
+    if data.pairing.token().is_none() {
+        return HttpResponse::ServiceUnavailable().body("Not paired with the printer");
+    }
+    let last_seen = data.status_watch.borrow().last_seen;
+    let max_age = chrono::Duration::from_std(READY_MAX_POLL_AGE).unwrap_or(chrono::TimeDelta::MAX);
+    match last_seen {
+        Some(x) if Utc::now() - x <= max_age => HttpResponse::Ok().body("ready"),
+        Some(x) => HttpResponse::ServiceUnavailable()
+            .body(format!("Last successful poll at {}", x.to_rfc3339())),
+        None => HttpResponse::ServiceUnavailable().body("No successful poll yet"),
+    }
+}
+
+#[get("/api/diagnostics")]
+pub async fn get_diagnostics(data: web::Data<AppState>) -> impl Responder {
+    // the firmware comes from the keep-alive loop, diagnostics sends nothing to the printer
+    let status = data.status_watch.borrow().clone();
+    let token_age = token_store::age().map(|x| x.as_secs());
+
+    HttpResponse::Ok().json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "printer": printer_info(),
+        "endpoint": endpoint(),
+        "connection": status.connection,
+        "last_seen": status.last_seen,
+        "token": {
+            "present": data.pairing.token().is_some(),
+            "pairing": data.pairing.state(),
+            "age_seconds": token_age,
+            "path": token_store::token_path(),
+            "encrypted": token_store::encrypted(),
+        },
+        "requests": metrics().request_diagnostics(),
+        "config": effective_config(),
+    }))
+}
+
+/// The settings from config.rs, secrets replaced by a marker
+fn effective_config() -> Value {
+    let redact = |x: Option<&str>| x.map(|_| "<redacted>");
+    let webhooks: Vec<_> = WEBHOOKS
+        .iter()
+        .map(|x| {
+            json!({
+                "url": target_name(x),
+                "secret": redact(x.secret),
+                "format": format!("{:?}", x.format),
+                "events": x.events,
+            })
+        })
+        .collect();
+    let smtp = SMTP.map(|x| {
+        json!({
+            "host": x.host,
+            "port": x.port,
+            "tls": format!("{:?}", x.tls),
+            "username": x.username,
+            "password": redact(x.password),
+            "from": x.from,
+            "to": x.to,
+        })
+    });
+    json!({
+        "snapmaker_endpoint": SNAPMAKER_ENDPOINT,
+        "discover_printer": DISCOVER_PRINTER,
+        "state_dir": token_store::state_dir(),
+        "token_key_file": TOKEN_KEY_FILE,
+        "printer_name": PRINTER_NAME,
+        "machine": MACHINE,
+        "serve_address": SERVE_ADDRESS,
+        "mdns_advertise": MDNS_ADVERTISE,
+        "mdns_name": MDNS_NAME,
+        "tls_address": TLS_ADDRESS,
+        "observer_address": OBSERVER_ADDRESS,
+        "read_only": READ_ONLY,
+        "max_printer_requests": MAX_PRINTER_REQUESTS,
+        "command_burst": COMMAND_BURST,
+        "command_rate_per_minute": COMMAND_RATE_PER_MINUTE,
+        "poll_interval_seconds": POLL_INTERVAL.as_secs_f64(),
+        "idle_poll_interval_seconds": IDLE_POLL_INTERVAL.as_secs_f64(),
+        "enclosure_poll_interval_seconds": ENCLOSURE_POLL_INTERVAL.as_secs_f64(),
+        "reconnect_max_backoff_seconds": RECONNECT_MAX_BACKOFF.as_secs_f64(),
+        "printer_connect_timeout_seconds": PRINTER_CONNECT_TIMEOUT.as_secs_f64(),
+        "printer_request_timeout_seconds": PRINTER_REQUEST_TIMEOUT.as_secs_f64(),
+        "printer_upload_timeout_seconds": PRINTER_UPLOAD_TIMEOUT.as_secs_f64(),
+        "stall_timeout_seconds": STALL_TIMEOUT.as_secs_f64(),
+        "thermal_watchdog": THERMAL_WATCHDOG.is_some(),
+        "mqtt": MQTT_BROKER.map(|(host, port)| json!({
+            "broker": format!("{host}:{port}"),
+            "username": MQTT_USERNAME,
+            "password": redact(MQTT_PASSWORD),
+        })),
+        "webhooks": webhooks,
+        "smtp": smtp,
+    })
+}
@@ -0,0 +1,21 @@
+use super::AppState;
+use actix_web::{HttpResponse, Responder, get, web};
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    /// Unix timestamp in milliseconds, only newer samples are returned
+    since: Option<i64>,
+}
+
+#[get("/api/history/temperature")]
+pub async fn get_temperature_history(
+    data: web::Data<AppState>,
+    query: web::Query<HistoryQuery>,
+) -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "window": data.history.window().as_secs(),
+        "samples": data.history.samples(query.since),
+    }))
+}
@@ -4,9 +4,16 @@ use tera::Context;
 
 #[get("/")]
 pub async fn get_index(data: web::Data<AppState>) -> impl Responder {
+    // nothing to control until the printer accepted us, observers see what it announces
+    if data.pairing.token().is_none() && !data.read_only {
+        return HttpResponse::Found()
+            .insert_header(("Location", "/pairing"))
+            .finish();
+    }
     let status = data.status_watch.borrow();
     let mut context = Context::new();
     context.insert("status", &*status);
+    context.insert("read_only", &data.read_only);
 
     match data.tera.render("index.html.tera", &context) {
         Ok(html) => HttpResponse::Ok().content_type("text/html").body(html),
@@ -43,6 +50,8 @@ pub async fn get_rendered_controls(data: web::Data<AppState>) -> impl Responder
     let status = data.status_watch.borrow();
     let mut context = Context::new();
     context.insert("status", &*status);
+    context.insert("locked", &data.interlock.is_locked());
+    context.insert("presets", &data.presets.list());
     match data.tera.render("controls.html.tera", &context) {
         Ok(html) => HttpResponse::Ok().content_type("text/html").body(html),
         Err(e) => {
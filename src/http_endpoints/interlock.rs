@@ -0,0 +1,43 @@
+use super::AppState;
+use actix_web::{HttpRequest, HttpResponse, Responder, get, post, web};
+use serde_json::json;
+
+#[post("/api/stop_print/arm")]
+pub async fn arm_stop_print(data: web::Data<AppState>) -> impl Responder {
+    let token = data.interlock.arm();
+    HttpResponse::Ok().json(json!({
+        "token": token,
+        "expires_in": data.interlock.window().as_secs(),
+    }))
+}
+
+#[get("/api/lock")]
+pub async fn get_lock(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(json!({ "locked": data.interlock.is_locked() }))
+}
+
+#[post("/api/lock")]
+pub async fn lock_controls(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    set_locked(&req, &data, true)
+}
+
+#[post("/api/unlock")]
+pub async fn unlock_controls(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    set_locked(&req, &data, false)
+}
+
+fn set_locked(req: &HttpRequest, data: &AppState, locked: bool) -> HttpResponse {
+    let result = data.interlock.set_locked(locked);
+    let action = if locked { "lock" } else { "unlock" };
+    data.audit.record(req, action, json!({}), &result.as_ref().map_err(|e| format!("{e:#}")));
+    match result {
+        Ok(()) => HttpResponse::Ok().json(json!({ "locked": locked })),
+        Err(e) => {
+            log::error!("{e:#}");
+            HttpResponse::InternalServerError().json(json!({
+                "locked": data.interlock.is_locked(),
+                "error": format!("{e:#}"),
+            }))
+        }
+    }
+}
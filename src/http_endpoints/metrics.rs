@@ -0,0 +1,12 @@
+use super::AppState;
+use actix_web::{HttpResponse, Responder, get, web};
+
+use crate::metrics::metrics;
+
+#[get("/metrics")]
+pub async fn get_metrics(data: web::Data<AppState>) -> impl Responder {
+    let status = data.status_watch.borrow().clone();
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics().render(&status))
+}
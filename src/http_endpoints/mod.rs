@@ -1,21 +1,202 @@
+pub(crate) mod audit;
+pub(crate) mod console;
+pub(crate) mod controls;
+pub(crate) mod discovery;
 pub(crate) mod enclosure;
+pub(crate) mod health;
+pub(crate) mod history;
 pub(crate) mod index;
-pub(crate) mod controls;
+pub(crate) mod interlock;
+pub(crate) mod metrics;
+pub(crate) mod pairing;
+pub(crate) mod presets;
+pub(crate) mod printer;
+pub(crate) mod scheduler;
 pub(crate) mod upload;
 pub(crate) mod version;
 
+pub(crate) use audit::*;
+pub(crate) use console::*;
+pub(crate) use controls::*;
+pub(crate) use discovery::*;
 pub(crate) use enclosure::*;
+pub(crate) use health::*;
+pub(crate) use history::*;
 pub(crate) use index::*;
-pub(crate) use controls::*;
-use std::sync::Arc;
-use tokio::sync::watch;
-use tera::Tera;
+pub(crate) use interlock::*;
+pub(crate) use metrics::*;
+pub(crate) use pairing::*;
+pub(crate) use presets::*;
+pub(crate) use printer::*;
+pub(crate) use scheduler::*;
 pub(crate) use upload::*;
 pub(crate) use version::*;
 
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use actix_web::web;
+use actix_web::{HttpRequest, HttpResponse, http::StatusCode};
+use serde_json::Value;
+use tera::Tera;
+use tokio::sync::watch;
+
+use crate::audit::AuditLog;
+use crate::console::{Console, Direction};
+use crate::gcode::UploadedFile;
+use crate::history::TemperatureHistory;
+use crate::interlock::{Command, Confirmation, Interlock, Refusal};
+use crate::pairing::Pairing;
+use crate::presets::Presets;
+use crate::scheduler::scheduler;
+
 #[derive(Clone)]
 pub struct AppState {
-    pub snapmaker_token: String,
+    pub pairing: Arc<Pairing>,
     pub status_watch: watch::Receiver<crate::status::PrinterStatus>,
     pub tera: Arc<Tera>,
+    pub audit: Arc<AuditLog>,
+    pub interlock: Arc<Interlock>,
+    pub console: Arc<Console>,
+    pub presets: Arc<Presets>,
+    pub history: Arc<TemperatureHistory>,
+    pub read_only: bool,
+    /// Thumbnail and name of the last upload, for notifications about its print
+    pub last_upload: Arc<Mutex<Option<UploadedFile>>>,
+    /// OctoPrint temperature offsets by heater (`tool0`, `bed`), added to targets we set
+    pub temperature_offsets: Arc<Mutex<HashMap<String, f64>>>,
+}
+
+impl AppState {
+    /// The printer token, empty until paired, `check_command` refuses commands before that
+    pub(crate) fn token(&self) -> String {
+        self.pairing.token().unwrap_or_default()
+    }
+
+    /// Runs the rate limit and interlock for a command, audits and answers refusals
+    pub(crate) fn check_command(
+        &self,
+        req: &HttpRequest,
+        action: &str,
+        parameters: &Value,
+        command: Command,
+        confirmation: &Confirmation,
+    ) -> Result<(), HttpResponse> {
+        let client = req
+            .peer_addr()
+            .map(|x| x.ip().to_string())
+            .unwrap_or_default();
+        if !scheduler().allow_command(&client) {
+            let message = "Too many commands, slow down";
+            self.audit.record(req, action, parameters.clone(), &Err::<(), _>(message));
+            return Err(HttpResponse::TooManyRequests().body(message));
+        }
+
+        if self.pairing.token().is_none() {
+            let message = "Not paired with the printer yet, see /pairing";
+            self.audit.record(req, action, parameters.clone(), &Err::<(), _>(message));
+            return Err(HttpResponse::ServiceUnavailable().body(message));
+        }
+
+        let status = self.status_watch.borrow().clone();
+        self.interlock
+            .check(command, &status, confirmation)
+            .map_err(|refusal| {
+                self.audit.record(req, action, parameters.clone(), &Err::<(), _>(&refusal));
+                refusal_response(&refusal)
+            })
+    }
+}
+
+impl AppState {
+    /// Sends G-code lines in order and keeps them in the console scrollback,
+    /// stops at the first failure
+    pub(crate) async fn run_gcode(&self, lines: &[String]) -> anyhow::Result<Vec<(String, String)>> {
+        let mut responses = Vec::new();
+        for line in lines {
+            self.console.push(Direction::Sent, line);
+            match crate::snapmaker_client::execute_gcode(&self.token(), line).await {
+                Ok(response) => {
+                    self.console.push(Direction::Received, &response);
+                    responses.push((line.clone(), response));
+                }
+                Err(e) => {
+                    self.console.push(Direction::Error, &e.to_string());
+                    return Err(e);
+                }
+            }
+        }
+        Ok(responses)
+    }
+
+    pub(crate) fn temperature_offset(&self, heater: &str) -> f64 {
+        let offsets = self.temperature_offsets.lock().unwrap_or_else(|e| e.into_inner());
+        offsets.get(heater).copied().unwrap_or_default()
+    }
+
+    pub(crate) fn set_temperature_offset(&self, heater: &str, offset: f64) {
+        let mut offsets = self.temperature_offsets.lock().unwrap_or_else(|e| e.into_inner());
+        offsets.insert(heater.to_string(), offset);
+    }
+}
+
+fn refusal_response(refusal: &Refusal) -> HttpResponse {
+    let status = match refusal {
+        Refusal::Locked => StatusCode::LOCKED,
+        Refusal::ConfirmationRequired => StatusCode::PRECONDITION_REQUIRED,
+        Refusal::InvalidState(_) => StatusCode::CONFLICT,
+    };
+    HttpResponse::build(status).body(refusal.to_string())
+}
+
+/// Endpoints that only look at the printer, safe for observers
+pub(crate) fn configure_read_only(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_health)
+        .service(get_readiness)
+        .service(get_version)
+        .service(get_status)
+        .service(get_rendered_status)
+        .service(get_index)
+        .service(get_pairing)
+        .service(get_pairing_page)
+        .service(get_rendered_pairing)
+        .service(get_discovery)
+        .service(get_rendered_discovery)
+        .service(get_scheduler_stats)
+        .service(get_metrics)
+        .service(get_settings)
+        .service(get_printer)
+        .service(get_temperature_history)
+        .service(actix_files::Files::new("/static", "static").show_files_listing());
+}
+
+pub(crate) fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(handle_upload)
+        .service(get_rendered_controls)
+        .service(set_enclosure_light)
+        .service(set_enclosure_fan)
+        .service(pause_print)
+        .service(stop_print)
+        .service(resume_print)
+        .service(arm_stop_print)
+        .service(get_lock)
+        .service(lock_controls)
+        .service(unlock_controls)
+        .service(get_audit)
+        .service(get_rendered_audit)
+        .service(send_printer_command)
+        .service(printhead_command)
+        .service(tool_command)
+        .service(bed_command)
+        .service(get_presets)
+        .service(put_preset)
+        .service(delete_preset)
+        .service(preheat)
+        .service(cooldown)
+        .service(get_console)
+        .service(get_rendered_console)
+        .service(get_diagnostics)
+        .service(retry_pairing)
+        .service(scan_for_printers);
+    configure_read_only(cfg);
 }
@@ -0,0 +1,44 @@
+use super::AppState;
+use crate::pairing::PairingState;
+use actix_web::{HttpRequest, HttpResponse, Responder, get, post, web};
+use serde_json::json;
+use tera::Context;
+
+#[get("/api/pairing")]
+pub async fn get_pairing(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(data.pairing.state())
+}
+
+#[post("/api/pairing/retry")]
+pub async fn retry_pairing(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    data.pairing.retry();
+    data.audit.record(&req, "retry_pairing", json!({}), &Ok::<(), String>(()));
+    HttpResponse::Accepted().json(data.pairing.state())
+}
+
+#[get("/pairing")]
+pub async fn get_pairing_page(data: web::Data<AppState>) -> impl Responder {
+    render(&data, "pairing.html.tera")
+}
+
+#[get("/render/pairing")]
+pub async fn get_rendered_pairing(data: web::Data<AppState>) -> impl Responder {
+    // paired, htmx takes the page over to the dashboard
+    if matches!(data.pairing.state(), PairingState::Paired { .. }) {
+        return HttpResponse::Ok().insert_header(("HX-Redirect", "/")).finish();
+    }
+    render(&data, "pairing_status.html.tera")
+}
+
+fn render(data: &AppState, template: &str) -> HttpResponse {
+    let mut context = Context::new();
+    context.insert("pairing", &data.pairing.state());
+    context.insert("read_only", &data.read_only);
+    match data.tera.render(template, &context) {
+        Ok(html) => HttpResponse::Ok().content_type("text/html").body(html),
+        Err(e) => {
+            log::error!("Failed to render pairing template: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to render template")
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use super::AppState;
+use crate::config::PRINTER_NAME;
+use crate::interlock::{Command, Confirmation};
+use crate::machine::machine;
+use crate::presets::Preset;
+use actix_web::{HttpRequest, HttpResponse, Responder, delete, get, post, put, web};
+use serde::Deserialize;
+use serde_json::json;
+use std::borrow::Cow;
+
+#[derive(Debug, Deserialize)]
+struct PresetBody {
+    nozzle: f64,
+    bed: f64,
+    #[serde(default)]
+    enclosure_fan: Option<u8>,
+}
+
+#[get("/api/presets")]
+pub async fn get_presets(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(data.presets.list())
+}
+
+#[put("/api/presets/{name}")]
+pub async fn put_preset(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    name: web::Path<String>,
+    body: web::Json<PresetBody>,
+) -> impl Responder {
+    let preset = Preset {
+        name: Cow::Owned(name.into_inner()),
+        nozzle: body.nozzle,
+        bed: body.bed,
+        enclosure_fan: body.enclosure_fan,
+    };
+    if let Err(e) = validate(&preset) {
+        return HttpResponse::BadRequest().body(e);
+    }
+    let parameters = serde_json::to_value(&preset).unwrap_or_default();
+    let result = data.presets.upsert(preset.clone());
+    data.audit.record(&req, "save_preset", parameters, &result);
+    match result {
+        Ok(_) => HttpResponse::Ok().json(preset),
+        Err(e) => {
+            log::error!("Failed to save preset: {:?}", e);
+            HttpResponse::InternalServerError().body(format!("Failed to save preset: {}", e))
+        }
+    }
+}
+
+#[delete("/api/presets/{name}")]
+pub async fn delete_preset(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    name: web::Path<String>,
+) -> impl Responder {
+    let result = data.presets.remove(&name);
+    data.audit
+        .record(&req, "delete_preset", json!({ "name": *name }), &result);
+    match result {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body(format!("No preset named {name}")),
+        Err(e) => {
+            log::error!("Failed to delete preset: {:?}", e);
+            HttpResponse::InternalServerError().body(format!("Failed to delete preset: {}", e))
+        }
+    }
+}
+
+#[post("/api/preheat/{profile}")]
+pub async fn preheat(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    profile: web::Path<String>,
+) -> impl Responder {
+    let Some(preset) = data.presets.get(&profile) else {
+        return HttpResponse::NotFound().body(format!("No preset named {profile}"));
+    };
+    let machine = machine();
+    let nozzle = (preset.nozzle + data.temperature_offset("tool0"))
+        .clamp(0.0, machine.max_nozzle_temperature());
+    let bed =
+        (preset.bed + data.temperature_offset("bed")).clamp(0.0, machine.max_bed_temperature());
+    let lines = vec![format!("M104 S{nozzle:.0}"), format!("M140 S{bed:.0}")];
+    let parameters = json!({ "profile": preset.name });
+    if let Err(response) = data.check_command(
+        &req,
+        "preheat",
+        &parameters,
+        Command::Preset,
+        &Confirmation::default(),
+    ) {
+        return response;
+    }
+
+    let mut result = data.run_gcode(&lines).await.map(|_| ());
+    if let (Ok(_), Some(fan)) = (&result, preset.enclosure_fan) {
+        result = crate::snapmaker_client::set_enclosure_fan(&data.token(), fan).await;
+    }
+    data.audit.record(&req, "preheat", parameters, &result);
+    match result {
+        Ok(_) => HttpResponse::Ok().body(format!("Preheating for {}", preset.name)),
+        Err(e) => {
+            log::error!("Failed to preheat: {:?}", e);
+            HttpResponse::InternalServerError().body(format!("Failed to preheat: {}", e))
+        }
+    }
+}
+
+#[post("/api/cooldown")]
+pub async fn cooldown(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let lines = vec!["M104 S0".to_string(), "M140 S0".to_string()];
+    if let Err(response) = data.check_command(
+        &req,
+        "cooldown",
+        &json!({}),
+        Command::Preset,
+        &Confirmation::default(),
+    ) {
+        return response;
+    }
+    let result = data.run_gcode(&lines).await;
+    data.audit.record(&req, "cooldown", json!({}), &result);
+    match result {
+        Ok(_) => HttpResponse::Ok().body("Cooling down"),
+        Err(e) => {
+            log::error!("Failed to cool down: {:?}", e);
+            HttpResponse::InternalServerError().body(format!("Failed to cool down: {}", e))
+        }
+    }
+}
+
+/// The parts of OctoPrint's settings apps read, temperature profiles mostly
+#[get("/api/settings")]
+pub async fn get_settings(data: web::Data<AppState>) -> impl Responder {
+    let profiles: Vec<_> = data
+        .presets
+        .list()
+        .into_iter()
+        .map(|x| json!({ "name": x.name, "extruder": x.nozzle, "bed": x.bed, "chamber": null }))
+        .collect();
+    HttpResponse::Ok().json(json!({
+        "appearance": { "name": PRINTER_NAME },
+        "temperature": {
+            "profiles": profiles,
+            "cutoff": 30,
+            "sendAutomatically": false,
+        },
+    }))
+}
+
+fn validate(preset: &Preset) -> Result<(), String> {
+    let machine = machine();
+    if preset.name.is_empty() || preset.name.len() > 32 {
+        return Err("Preset names need 1 to 32 characters".to_string());
+    }
+    if !(0.0..=machine.max_nozzle_temperature()).contains(&preset.nozzle) {
+        return Err(format!(
+            "Nozzle temperature must be between 0 and {}°C",
+            machine.max_nozzle_temperature()
+        ));
+    }
+    if !(0.0..=machine.max_bed_temperature()).contains(&preset.bed) {
+        return Err(format!(
+            "Bed temperature must be between 0 and {}°C",
+            machine.max_bed_temperature()
+        ));
+    }
+    if preset.enclosure_fan.is_some_and(|x| x > 100) {
+        return Err("Enclosure fan must be between 0 and 100%".to_string());
+    }
+    Ok(())
+}
@@ -0,0 +1,428 @@
+use super::AppState;
+use crate::config::JOG_FEEDRATE;
+use crate::interlock::{Command, Confirmation, is_print_safe};
+use crate::machine::machine;
+use actix_web::{HttpRequest, HttpResponse, Responder, get, post, web};
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use std::collections::HashMap;
+
+/// Bodies of OctoPrint's printer operations, see
+/// https://docs.octoprint.org/en/master/api/printer.html
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum PrintheadCommand {
+    Jog {
+        x: Option<f64>,
+        y: Option<f64>,
+        z: Option<f64>,
+        #[serde(default)]
+        absolute: bool,
+        speed: Option<f64>,
+    },
+    Home {
+        axes: Vec<String>,
+    },
+    Feedrate {
+        factor: f64,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum ToolCommand {
+    Target { targets: HashMap<String, f64> },
+    Offset { offsets: HashMap<String, f64> },
+    Select { tool: String },
+    Extrude { amount: f64, speed: Option<f64> },
+    Flowrate { factor: f64 },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum BedCommand {
+    Target { target: f64 },
+    Offset { offset: f64 },
+}
+
+#[derive(Debug, Deserialize)]
+struct PrinterQuery {
+    #[serde(default)]
+    history: bool,
+    limit: Option<usize>,
+    /// Comma separated parts to leave out: `temperature`, `sd`, `state`
+    exclude: Option<String>,
+}
+
+/// What a request turned into: the G-code to send and whether it moves anything
+struct Translation {
+    lines: Vec<String>,
+    motion: bool,
+    /// Temperature offsets to remember once the printer took the G-code
+    offsets: Vec<(String, f64)>,
+    /// Sent after `lines` whether they worked or not, e.g. back to absolute positioning
+    restore: Vec<String>,
+}
+
+impl Translation {
+    fn motion(lines: Vec<String>) -> Result<Self, String> {
+        Ok(Self {
+            lines,
+            motion: true,
+            offsets: Vec::new(),
+            restore: Vec::new(),
+        })
+    }
+
+    /// A move in a temporary mode that `restore` switches back from
+    fn motion_restoring(lines: Vec<String>, restore: Vec<String>) -> Result<Self, String> {
+        Ok(Self {
+            restore,
+            ..Self::motion(lines)?
+        })
+    }
+
+    fn settings(lines: Vec<String>) -> Result<Self, String> {
+        Ok(Self {
+            lines,
+            motion: false,
+            offsets: Vec::new(),
+            restore: Vec::new(),
+        })
+    }
+
+    fn offsets(lines: Vec<String>, offsets: Vec<(String, f64)>) -> Result<Self, String> {
+        Ok(Self {
+            offsets,
+            ..Self::settings(lines)?
+        })
+    }
+}
+
+/// OctoPrint's printer state with temperatures and, on request, their history
+#[get("/api/printer")]
+pub async fn get_printer(data: web::Data<AppState>, query: web::Query<PrinterQuery>) -> impl Responder {
+    let status = data.status_watch.borrow().clone();
+    if !status.is_operational() {
+        return HttpResponse::Conflict().body("Printer is not operational");
+    }
+    // clients show these temperatures as live, don't hand them the last known ones
+    if status.stale {
+        return HttpResponse::Conflict().body("Printer readings are stale");
+    }
+    let excluded: Vec<&str> = query
+        .exclude
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .collect();
+    let mut body = Map::new();
+
+    if !excluded.contains(&"temperature") {
+        let mut temperature = json!({
+            "tool0": {
+                "actual": status.nozzle_temperature,
+                "target": status.nozzle_target_temperature,
+                "offset": data.temperature_offset("tool0"),
+            },
+            "bed": {
+                "actual": status.heated_bed_temperature,
+                "target": status.heated_bed_target_temperature,
+                "offset": data.temperature_offset("bed"),
+            },
+        });
+        if query.history {
+            let samples = data.history.samples(None);
+            let skip = samples.len().saturating_sub(query.limit.unwrap_or(samples.len()));
+            let history: Vec<_> = samples
+                .iter()
+                .skip(skip)
+                .map(|x| {
+                    json!({
+                        "time": x.timestamp.timestamp(),
+                        "tool0": { "actual": x.nozzle, "target": x.nozzle_target },
+                        "bed": { "actual": x.bed, "target": x.bed_target },
+                    })
+                })
+                .collect();
+            temperature["history"] = json!(history);
+        }
+        body.insert("temperature".to_string(), temperature);
+    }
+    if !excluded.contains(&"sd") {
+        body.insert("sd".to_string(), json!({ "ready": false }));
+    }
+    if !excluded.contains(&"state") {
+        body.insert("state".to_string(), printer_state(&status.status));
+    }
+    HttpResponse::Ok().json(body)
+}
+
+fn printer_state(state: &str) -> Value {
+    let (text, printing, paused) = match state {
+        "RUNNING" => ("Printing", true, false),
+        "PAUSED" => ("Paused", false, true),
+        "UNKNOWN" => ("Offline", false, false),
+        _ => ("Operational", false, false),
+    };
+    let operational = state != "UNKNOWN";
+    json!({
+        "text": text,
+        "flags": {
+            "operational": operational,
+            "printing": printing,
+            "paused": paused,
+            "pausing": false,
+            "cancelling": false,
+            "resuming": false,
+            "finishing": false,
+            "ready": operational && !printing && !paused,
+            "error": false,
+            "closedOrError": !operational,
+            "sdReady": false,
+        },
+    })
+}
+
+#[post("/api/printer/printhead")]
+pub async fn printhead_command(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    body: web::Json<Value>,
+) -> impl Responder {
+    let translation = serde_json::from_value(body.0.clone())
+        .map_err(|e| e.to_string())
+        .and_then(|command| translate_printhead(command, &data));
+    run_translation(&req, &data, "printhead", body.0, translation).await
+}
+
+#[post("/api/printer/tool")]
+pub async fn tool_command(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    body: web::Json<Value>,
+) -> impl Responder {
+    let translation = serde_json::from_value(body.0.clone())
+        .map_err(|e| e.to_string())
+        .and_then(|command| translate_tool(command, &data));
+    run_translation(&req, &data, "tool", body.0, translation).await
+}
+
+#[post("/api/printer/bed")]
+pub async fn bed_command(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    body: web::Json<Value>,
+) -> impl Responder {
+    let translation = serde_json::from_value(body.0.clone())
+        .map_err(|e| e.to_string())
+        .and_then(|command| translate_bed(command, &data));
+    run_translation(&req, &data, "bed", body.0, translation).await
+}
+
+async fn run_translation(
+    req: &HttpRequest,
+    data: &AppState,
+    action: &str,
+    parameters: Value,
+    translation: Result<Translation, String>,
+) -> HttpResponse {
+    let translation = match translation {
+        Ok(x) => x,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let command = if translation.motion {
+        Command::Motion
+    } else {
+        Command::Gcode {
+            print_safe: translation.lines.iter().all(|x| is_print_safe(x)),
+        }
+    };
+    if let Err(response) =
+        data.check_command(req, action, &parameters, command, &Confirmation::default())
+    {
+        return response;
+    }
+    let result = data.run_gcode(&translation.lines).await;
+    if !translation.restore.is_empty()
+        && let Err(e) = data.run_gcode(&translation.restore).await
+    {
+        log::error!("Failed to restore after {action} command: {e:?}");
+    }
+    if result.is_ok() {
+        for (heater, offset) in translation.offsets {
+            data.set_temperature_offset(&heater, offset);
+        }
+    }
+    data.audit.record(req, action, parameters, &result);
+    match result {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => {
+            log::error!("Failed to run {} command: {:?}", action, e);
+            HttpResponse::InternalServerError().body(format!("Failed to run {action} command: {e}"))
+        }
+    }
+}
+
+fn translate_printhead(command: PrintheadCommand, data: &AppState) -> Result<Translation, String> {
+    match command {
+        PrintheadCommand::Jog {
+            x,
+            y,
+            z,
+            absolute,
+            speed,
+        } => {
+            let status = data.status_watch.borrow().clone();
+            if !status.homed {
+                return Err("Home the printer before jogging".to_string());
+            }
+            let (max_x, max_y, max_z) = machine().work_volume();
+            let mut moves = Vec::new();
+            for (axis, value, position, max) in [
+                ("X", x, status.x, max_x),
+                ("Y", y, status.y, max_y),
+                ("Z", z, status.z, max_z),
+            ] {
+                let Some(value) = value else { continue };
+                let target = if absolute { value } else { position + value };
+                // relative so a move stays relative to where the head really is
+                let distance = target.clamp(0.0, max) - position;
+                if distance.abs() >= 0.01 {
+                    moves.push(format!("{axis}{distance:.2}"));
+                }
+            }
+            if moves.is_empty() {
+                return Err("Nothing to move, the target is outside the work volume".to_string());
+            }
+            let feedrate = positive_speed(speed)?.unwrap_or(JOG_FEEDRATE);
+            Translation::motion_restoring(
+                vec![
+                    "G91".to_string(),
+                    format!("G0 {} F{feedrate:.0}", moves.join(" ")),
+                ],
+                vec!["G90".to_string()],
+            )
+        }
+        PrintheadCommand::Home { axes } => {
+            let mut axes: Vec<String> = axes.iter().map(|x| x.to_ascii_uppercase()).collect();
+            if let Some(axis) = axes.iter().find(|x| !["X", "Y", "Z"].contains(&x.as_str())) {
+                return Err(format!("Unknown axis {axis}"));
+            }
+            axes.sort();
+            axes.dedup();
+            Translation::motion(vec![format!("G28 {}", axes.join(" ")).trim().to_string()])
+        }
+        PrintheadCommand::Feedrate { factor } => {
+            let percent = factor_percent(factor, 50.0, 200.0)?;
+            Translation::settings(vec![format!("M220 S{percent:.0}")])
+        }
+    }
+}
+
+fn translate_tool(command: ToolCommand, data: &AppState) -> Result<Translation, String> {
+    let max = machine().max_nozzle_temperature();
+    match command {
+        ToolCommand::Target { targets } => {
+            let mut lines = Vec::new();
+            for (tool, target) in targets {
+                let offset = data.temperature_offset(&tool);
+                let target = check_temperature(&tool, target, max)?;
+                let heating = if target > 0.0 { target + offset } else { 0.0 };
+                lines.push(format!("M104 T{} S{:.0}", tool_index(&tool)?, heating.clamp(0.0, max)));
+            }
+            Translation::settings(lines)
+        }
+        ToolCommand::Offset { offsets } => {
+            let status = data.status_watch.borrow().clone();
+            let mut lines = Vec::new();
+            for (tool, offset) in &offsets {
+                let (index, offset) = (tool_index(tool)?, *offset);
+                if !(-50.0..=50.0).contains(&offset) {
+                    return Err(format!("Offset for {tool} must be between -50 and 50"));
+                }
+                let previous = data.temperature_offset(tool);
+                // shift a target that is already set, targets set later pick up the offset
+                if index == 0 && status.nozzle_target_temperature > 0.0 {
+                    let target = status.nozzle_target_temperature - previous + offset;
+                    lines.push(format!("M104 T0 S{:.0}", target.clamp(0.0, max)));
+                }
+            }
+            Translation::offsets(lines, offsets.into_iter().collect())
+        }
+        ToolCommand::Select { tool } => {
+            Translation::motion(vec![format!("T{}", tool_index(&tool)?)])
+        }
+        ToolCommand::Extrude { amount, speed } => {
+            if !(-100.0..=100.0).contains(&amount) {
+                return Err("Extrude amount must be between -100 and 100 mm".to_string());
+            }
+            let feedrate = positive_speed(speed)?.unwrap_or(300.0);
+            Translation::motion_restoring(
+                vec!["M83".to_string(), format!("G1 E{amount:.2} F{feedrate:.0}")],
+                vec!["M82".to_string()],
+            )
+        }
+        ToolCommand::Flowrate { factor } => {
+            let percent = factor_percent(factor, 75.0, 125.0)?;
+            Translation::settings(vec![format!("M221 S{percent:.0}")])
+        }
+    }
+}
+
+fn translate_bed(command: BedCommand, data: &AppState) -> Result<Translation, String> {
+    let max = machine().max_bed_temperature();
+    match command {
+        BedCommand::Target { target } => {
+            let target = check_temperature("bed", target, max)?;
+            let offset = data.temperature_offset("bed");
+            let heating = if target > 0.0 { target + offset } else { 0.0 };
+            Translation::settings(vec![format!("M140 S{:.0}", heating.clamp(0.0, max))])
+        }
+        BedCommand::Offset { offset } => {
+            if !(-50.0..=50.0).contains(&offset) {
+                return Err("Offset for bed must be between -50 and 50".to_string());
+            }
+            let previous = data.temperature_offset("bed");
+            let status = data.status_watch.borrow().clone();
+            let mut lines = Vec::new();
+            if status.heated_bed_target_temperature > 0.0 {
+                let target = status.heated_bed_target_temperature - previous + offset;
+                lines.push(format!("M140 S{:.0}", target.clamp(0.0, max)));
+            }
+            Translation::offsets(lines, vec![("bed".to_string(), offset)])
+        }
+    }
+}
+
+/// `tool0` -> 0
+fn tool_index(tool: &str) -> Result<u32, String> {
+    tool.strip_prefix("tool")
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| format!("Unknown tool {tool}"))
+}
+
+fn check_temperature(heater: &str, target: f64, max: f64) -> Result<f64, String> {
+    if !(0.0..=max).contains(&target) {
+        return Err(format!("Target for {heater} must be between 0 and {max}°C"));
+    }
+    Ok(target)
+}
+
+/// A feedrate of 0 or less would leave the printer stuck on the move
+fn positive_speed(speed: Option<f64>) -> Result<Option<f64>, String> {
+    match speed {
+        Some(x) if x <= 0.0 => Err("Speed must be greater than 0".to_string()),
+        x => Ok(x),
+    }
+}
+
+/// OctoPrint accepts factors both as percent (110) and as ratio (1.1)
+fn factor_percent(factor: f64, min: f64, max: f64) -> Result<f64, String> {
+    let percent = if factor <= 10.0 { factor * 100.0 } else { factor };
+    if !(min..=max).contains(&percent) {
+        return Err(format!("Factor must be between {min}% and {max}%"));
+    }
+    Ok(percent)
+}
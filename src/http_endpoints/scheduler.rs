@@ -0,0 +1,8 @@
+use actix_web::{HttpResponse, Responder, get};
+
+use crate::scheduler::scheduler;
+
+#[get("/api/scheduler")]
+pub async fn get_scheduler_stats() -> impl Responder {
+    HttpResponse::Ok().json(scheduler().stats())
+}
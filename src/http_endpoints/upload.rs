@@ -3,9 +3,18 @@ use std::time::Duration;
 use actix_multipart::form::MultipartForm;
 use actix_multipart::form::tempfile::TempFile;
 use actix_multipart::form::text::Text;
-use actix_web::{Error, HttpResponse, post, web};
+use actix_web::{Error, FromRequest, HttpRequest, HttpResponse, dev::Payload, error, post, web};
+use futures::future::{Ready, ready};
+use log::warn;
+use serde_json::json;
 
-use crate::{http_endpoints::AppState, snapmaker_client};
+use crate::{
+    gcode::{self, UploadedFile},
+    http_endpoints::AppState,
+    interlock::{Command, Confirmation},
+    shutdown::{Phase, UploadGuard, shutdown},
+    snapmaker_client,
+};
 
 #[derive(Debug, MultipartForm)]
 struct UploadForm {
@@ -14,8 +23,31 @@ struct UploadForm {
     print: Text<bool>,
 }
 
+/// A place among the uploads that are let finish on shutdown. Extracted before
+/// the form, so a proxy that is draining refuses before it receives the file
+struct UploadSlot {
+    _guard: UploadGuard,
+}
+
+impl FromRequest for UploadSlot {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(_: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            shutdown()
+                .start_upload()
+                .map(|_guard| Self { _guard })
+                .ok_or_else(|| error::ErrorServiceUnavailable("Shutting down, not taking uploads")),
+        )
+    }
+}
+
 #[post("/api/files/local")]
 pub(crate) async fn handle_upload(
+    req: HttpRequest,
+    // extractors run in order, this one has to come before the form
+    _upload: UploadSlot,
     MultipartForm(form): MultipartForm<UploadForm>,
     data: web::Data<AppState>,
 ) -> Result<HttpResponse, Error> {
@@ -24,18 +56,67 @@ pub(crate) async fn handle_upload(
         Some(x) => x,
         None => return Ok(HttpResponse::BadRequest().body("No filename provided")),
     };
-    match snapmaker_client::upload_file_to_snapmaker(&data.snapmaker_token, file_path, &file_name)
-        .await
-    {
-        Ok(_) => (),
+    let parameters = json!({ "file_name": file_name, "size": form.file.size, "print": form.print.0 });
+    if let Err(response) = data.check_command(
+        &req,
+        "upload",
+        &parameters,
+        Command::Upload { print: form.print.0 },
+        &Confirmation::default(),
+    ) {
+        return Ok(response);
+    }
+    let thumbnail_path = file_path.to_path_buf();
+    let thumbnail = match web::block(move || gcode::extract_thumbnail(&thumbnail_path)).await? {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Failed to look for a thumbnail in {file_name}: {e}");
+            None
+        }
+    };
+    let token = data.token();
+    let upload = snapmaker_client::upload_file_to_snapmaker(&token, file_path, &file_name);
+    let result = tokio::select! {
+        result = upload => result,
+        _ = shutdown().reached(Phase::Aborting) => {
+            let message = "Upload aborted because the proxy is shutting down";
+            data.audit.record(&req, "upload", parameters, &Err::<(), _>(message));
+            return Ok(HttpResponse::ServiceUnavailable().body(message));
+        }
+    };
+    data.audit.record(&req, "upload", parameters, &result);
+    match result {
+        Ok(_) => {
+            *data.last_upload.lock().unwrap_or_else(|e| e.into_inner()) = Some(UploadedFile {
+                file_name: file_name.clone(),
+                thumbnail,
+            });
+        }
         Err(e) => {
             return Ok(HttpResponse::InternalServerError()
                 .body(format!("Upload Error from snapmaker: {e:?}")));
         }
     };
     if form.print.0 {
-        tokio::time::sleep(Duration::from_secs(2)).await;
-        match snapmaker_client::start_print(&data.snapmaker_token).await {
+        // the printer has the file now, when cut short it waits on the touchscreen
+        // instead of starting after we are gone
+        let aborted = tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(2)) => false,
+            _ = shutdown().reached(Phase::Aborting) => true,
+        };
+        if aborted {
+            let message = "File uploaded, print not started because the proxy is shutting down";
+            data.audit.record(
+                &req,
+                "start_print",
+                json!({ "file_name": file_name }),
+                &Err::<(), _>(message),
+            );
+            return Ok(HttpResponse::ServiceUnavailable().body(message));
+        }
+        let result = snapmaker_client::start_print(&data.token()).await;
+        data.audit.record(&req, "start_print", json!({ "file_name": file_name }), &result);
+        match result {
             Ok(_) => (),
             Err(e) => {
                 return Ok(HttpResponse::InternalServerError()
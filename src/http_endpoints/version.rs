@@ -2,11 +2,15 @@ use actix_web::get;
 use actix_web::web::Json;
 use serde::Serialize;
 
+/// The OctoPrint API and server versions we answer like
+pub(crate) const OCTOPRINT_API_VERSION: &str = "0.1";
+pub(crate) const OCTOPRINT_SERVER_VERSION: &str = "1.9.0";
+
 #[get("/api/version")]
 pub(crate) async fn get_version() -> Json<OctoVersion> {
     Json(OctoVersion {
-        api: "0.1".to_string(),
-        server: "1.9.0".to_string(),
+        api: OCTOPRINT_API_VERSION.to_string(),
+        server: OCTOPRINT_SERVER_VERSION.to_string(),
         text: "OctoPrint (Snapmaker Proxy)".to_string(),
     })
 }
@@ -0,0 +1,275 @@
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::PathBuf,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use log::info;
+use rand::{Rng, distributions::Alphanumeric};
+
+use crate::{config::GCODE_ALLOWED_WHILE_PRINTING, status::PrinterStatus};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Pause,
+    Stop,
+    Resume,
+    Enclosure,
+    Upload { print: bool },
+    /// `print_safe` when every line is on the allow-list for active prints
+    Gcode { print_safe: bool },
+    /// Jogging, homing, extruding or switching tools
+    Motion,
+    /// Preheating or cooling down, would change the heaters of a running job
+    Preset,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Refusal {
+    Locked,
+    ConfirmationRequired,
+    InvalidState(String),
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refusal::Locked => write!(f, "Controls are locked"),
+            Refusal::ConfirmationRequired => write!(
+                f,
+                "Stopping a print needs confirm=true or a token from /api/stop_print/arm"
+            ),
+            Refusal::InvalidState(x) => write!(f, "{x}"),
+        }
+    }
+}
+
+impl std::error::Error for Refusal {}
+
+/// What a stop request brought along to prove it is intended
+#[derive(Debug, Default)]
+pub struct Confirmation {
+    pub confirm: bool,
+    pub token: Option<String>,
+}
+
+/// Server-side guard in front of every control command
+pub struct Interlock {
+    locked: AtomicBool,
+    /// Armed tokens with when they were handed out, every client gets its own
+    armed: Mutex<HashMap<String, Instant>>,
+    window: Duration,
+    /// Exists while the controls are locked, so a restart doesn't unlock them
+    lock_file: Option<PathBuf>,
+}
+
+impl Interlock {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            armed: Mutex::default(),
+            window,
+            lock_file: None,
+        }
+    }
+
+    /// Locked when `lock_file` was left behind by an earlier run
+    pub fn load(window: Duration, lock_file: &str) -> Self {
+        let lock_file = PathBuf::from(lock_file);
+        let locked = lock_file.exists();
+        if locked {
+            info!("Controls are locked since before the restart, see {}", lock_file.display());
+        }
+        Self {
+            locked: AtomicBool::new(locked),
+            lock_file: Some(lock_file),
+            ..Self::new(window)
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::SeqCst)
+    }
+
+    /// Locking holds even when it can't be saved, unlocking only once it was
+    pub fn set_locked(&self, locked: bool) -> anyhow::Result<()> {
+        if locked {
+            self.locked.store(true, Ordering::SeqCst);
+        }
+        if let Some(path) = &self.lock_file {
+            let saved = if locked {
+                fs::write(path, "locked\n")
+            } else {
+                match fs::remove_file(path) {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                    x => x,
+                }
+            };
+            saved.with_context(|| format!("Failed to save the lock state to {}", path.display()))?;
+        }
+        self.locked.store(locked, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Hands out a one-time token that confirms a stop within the window
+    pub fn arm(&self) -> String {
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect();
+        let mut armed = self.armed.lock().unwrap_or_else(|e| e.into_inner());
+        armed.retain(|_, armed_at| armed_at.elapsed() <= self.window);
+        armed.insert(token.clone(), Instant::now());
+        token
+    }
+
+    pub fn check(
+        &self,
+        command: Command,
+        status: &PrinterStatus,
+        confirmation: &Confirmation,
+    ) -> Result<(), Refusal> {
+        if self.is_locked() {
+            return Err(Refusal::Locked);
+        }
+        check_state(command, status)?;
+        if command == Command::Stop && !confirmation.confirm {
+            self.consume_token(confirmation.token.as_deref())?;
+        }
+        Ok(())
+    }
+
+    fn consume_token(&self, token: Option<&str>) -> Result<(), Refusal> {
+        let mut armed = self.armed.lock().unwrap_or_else(|e| e.into_inner());
+        armed.retain(|_, armed_at| armed_at.elapsed() <= self.window);
+        // a wrong guess leaves the tokens of others alone
+        match token.and_then(|x| armed.remove(x)) {
+            Some(_) => Ok(()),
+            None => Err(Refusal::ConfirmationRequired),
+        }
+    }
+}
+
+fn check_state(command: Command, status: &PrinterStatus) -> Result<(), Refusal> {
+    let state = status.status.as_str();
+    let refuse = |x: &str| {
+        Err(Refusal::InvalidState(format!(
+            "Cannot {x} while printer is {state}"
+        )))
+    };
+    match command {
+        Command::Pause if state != "RUNNING" => refuse("pause"),
+        Command::Resume if state != "PAUSED" => refuse("resume"),
+        Command::Stop if state != "RUNNING" && state != "PAUSED" => refuse("stop"),
+        Command::Upload { print: true } if state == "RUNNING" || state == "PAUSED" => {
+            refuse("start a print")
+        }
+        Command::Motion if state == "RUNNING" || state == "PAUSED" => refuse("move the printer"),
+        Command::Preset if state == "RUNNING" || state == "PAUSED" => refuse("apply a preset"),
+        Command::Gcode { print_safe: false } if state == "RUNNING" || state == "PAUSED" => {
+            refuse("send G-code outside the allow-list")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Whether a single G-code line may be sent while a print is active
+pub fn is_print_safe(line: &str) -> bool {
+    let code = line.split_whitespace().next().unwrap_or_default();
+    GCODE_ALLOWED_WHILE_PRINTING
+        .iter()
+        .any(|x| x.eq_ignore_ascii_case(code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn running() -> PrinterStatus {
+        PrinterStatus {
+            status: "RUNNING".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn stop_with(token: Option<&str>) -> Confirmation {
+        Confirmation {
+            confirm: false,
+            token: token.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn armed_tokens_dont_replace_each_other() {
+        let interlock = Interlock::new(Duration::from_secs(30));
+        let http = interlock.arm();
+        // an MQTT press arms its own token in between
+        let mqtt = interlock.arm();
+        assert_ne!(http, mqtt);
+        let stop = |token| interlock.check(Command::Stop, &running(), &stop_with(token));
+        assert_eq!(stop(Some(&http)), Ok(()));
+        // each token confirms once
+        assert_eq!(stop(Some(&http)), Err(Refusal::ConfirmationRequired));
+        assert_eq!(stop(Some(&mqtt)), Ok(()));
+    }
+
+    #[test]
+    fn wrong_token_keeps_the_armed_one() {
+        let interlock = Interlock::new(Duration::from_secs(30));
+        let token = interlock.arm();
+        let stop = |token| interlock.check(Command::Stop, &running(), &stop_with(token));
+        assert_eq!(stop(None), Err(Refusal::ConfirmationRequired));
+        assert_eq!(stop(Some("guess")), Err(Refusal::ConfirmationRequired));
+        assert_eq!(stop(Some(&token)), Ok(()));
+    }
+
+    #[test]
+    fn armed_tokens_expire() {
+        let interlock = Interlock::new(Duration::ZERO);
+        let token = interlock.arm();
+        std::thread::sleep(Duration::from_millis(5));
+        let refused = interlock.check(Command::Stop, &running(), &stop_with(Some(&token)));
+        assert_eq!(refused, Err(Refusal::ConfirmationRequired));
+    }
+
+    #[test]
+    fn lock_survives_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("controls.locked");
+        let path = path.to_str().unwrap();
+        let window = Duration::from_secs(30);
+
+        let interlock = Interlock::load(window, path);
+        assert!(!interlock.is_locked());
+        interlock.set_locked(true).unwrap();
+        assert!(Interlock::load(window, path).is_locked());
+
+        interlock.set_locked(false).unwrap();
+        assert!(!Interlock::load(window, path).is_locked());
+        // unlocking twice is fine
+        interlock.set_locked(false).unwrap();
+    }
+
+    #[test]
+    fn locks_even_when_it_cannot_be_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing").join("controls.locked");
+        let interlock = Interlock::load(Duration::from_secs(30), path.to_str().unwrap());
+        assert!(interlock.set_locked(true).is_err());
+        assert!(interlock.is_locked());
+        let status = PrinterStatus::default();
+        let refused = interlock.check(Command::Pause, &status, &Confirmation::default());
+        assert_eq!(refused, Err(Refusal::Locked));
+    }
+}
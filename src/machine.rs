@@ -0,0 +1,64 @@
+use serde::Serialize;
+
+use crate::{config::MACHINE, snapmaker_client::printer_info};
+
+/// Snapmaker 2.0 sizes, the limits are those of the 3D printing module
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Machine {
+    A150,
+    A250,
+    A350,
+}
+
+impl Machine {
+    /// The size in the series the printer reports when connecting, e.g. "Snapmaker 2.0 A350"
+    pub fn from_model(model: &str) -> Option<Self> {
+        match model.split_whitespace().last()? {
+            x if x.eq_ignore_ascii_case("A150") => Some(Machine::A150),
+            x if x.eq_ignore_ascii_case("A250") => Some(Machine::A250),
+            x if x.eq_ignore_ascii_case("A350") => Some(Machine::A350),
+            _ => None,
+        }
+    }
+
+    /// Work volume in mm as (x, y, z)
+    pub fn work_volume(&self) -> (f64, f64, f64) {
+        match self {
+            Machine::A150 => (160.0, 160.0, 145.0),
+            Machine::A250 => (230.0, 250.0, 235.0),
+            Machine::A350 => (320.0, 350.0, 330.0),
+        }
+    }
+
+    pub fn max_nozzle_temperature(&self) -> f64 {
+        275.0
+    }
+
+    pub fn max_bed_temperature(&self) -> f64 {
+        match self {
+            Machine::A150 => 110.0,
+            Machine::A250 | Machine::A350 => 100.0,
+        }
+    }
+}
+
+/// The configured machine, else the one the printer reported, the smallest one when unknown
+/// so moves stay inside any of them
+pub fn machine() -> Machine {
+    MACHINE
+        .or_else(|| printer_info().model.as_deref().and_then(Machine::from_model))
+        .unwrap_or(Machine::A150)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_size_from_the_model() {
+        assert_eq!(Machine::from_model("Snapmaker 2.0 A350"), Some(Machine::A350));
+        assert_eq!(Machine::from_model("Snapmaker 2 Model a250"), Some(Machine::A250));
+        assert_eq!(Machine::from_model("Snapmaker Original"), None);
+        assert_eq!(Machine::from_model(""), None);
+    }
+}
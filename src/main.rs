@@ -1,37 +1,105 @@
+mod activity;
+mod audit;
+mod cli;
 mod config;
+mod console;
+mod discovery;
+mod email;
+mod events;
+mod gcode;
+mod history;
 mod http_endpoints;
+mod interlock;
+mod machine;
+mod mdns;
+mod metrics;
+mod mqtt;
+mod pairing;
+mod presets;
+mod scheduler;
+mod shutdown;
 mod snapmaker_client;
 mod status;
+mod systemd;
+mod thermal;
+mod tls;
+mod token_store;
+mod webhooks;
 
-use actix_web::{App, HttpServer, middleware::Logger, web};
-use log::info;
+use actix_web::{App, HttpServer, dev::Service, middleware::Logger, web};
+use clap::Parser;
+use log::{info, warn};
 
-use crate::config::SERVE_ADDRESS;
+use crate::activity::activity;
+use crate::audit::AuditLog;
+use crate::cli::{Cli, Command};
+use crate::config::{
+    AUDIT_LOG_FILE, CONSOLE_SCROLLBACK, DEFAULT_PRESETS, LOCK_FILE, MDNS_ADVERTISE,
+    OBSERVER_ADDRESS, PRESETS_FILE, READ_ONLY, SERVE_ADDRESS, STOP_CONFIRM_WINDOW,
+    TEMPERATURE_HISTORY_FILE, TEMPERATURE_HISTORY_WINDOW, TLS_ADDRESS, TLS_REDIRECT_HTTP,
+    UPLOAD_DRAIN_TIMEOUT,
+};
+use crate::console::Console;
+use crate::email::email_loop;
+use crate::events::create_event_channel;
+use crate::history::TemperatureHistory;
 use crate::http_endpoints::AppState;
-use crate::snapmaker_client::keep_alive_loop;
-use crate::status::create_status_watch;
-use std::sync::Arc;
+use crate::interlock::Interlock;
+use crate::mqtt::{MqttControl, mqtt_loop};
+use crate::pairing::{Pairing, pairing_loop};
+use crate::presets::Presets;
+use crate::shutdown::shutdown_on_signal;
+use crate::snapmaker_client::{Unauthorized, keep_alive_loop};
+use crate::status::{PrinterStatus, create_status_watch};
+use crate::thermal::{WatchdogControl, thermal_watchdog_loop};
+use crate::webhooks::webhook_loop;
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tera::Tera;
 
 #[actix_web::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> ExitCode {
     // Initialize logging
     env_logger::init();
+    let cli = Cli::parse();
+    match cli.command {
+        None | Some(Command::Serve) => match serve().await {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("Error: {e:?}");
+                ExitCode::FAILURE
+            }
+        },
+        Some(command) => cli::run(command, cli.proxy).await,
+    }
+}
+
+async fn serve() -> anyhow::Result<()> {
     info!("Starting Snapmaker Proxy Server");
 
-    // Get Snapmaker token
-    let token = match snapmaker_client::get_snapmaker_token().await {
-        Ok(token) => {
-            info!("Successfully obtained Snapmaker token");
-            token
-        }
-        Err(e) => {
-            anyhow::bail!("Failed to get Snapmaker token: {}", e);
+    // Finds DISCOVER_PRINTER before pairing needs it
+    tokio::spawn(discovery::discovery_loop());
+    // Pairing runs in the background, the web UI shows how it's going
+    let pairing = Arc::new(Pairing::default());
+    tokio::spawn(pairing_loop(pairing.clone(), READ_ONLY));
+    let mdns = MDNS_ADVERTISE.then(|| tokio::spawn(mdns::mdns_loop(pairing.clone())));
+
+    // Temperatures appended by the keep-alive loop
+    let history = Arc::new(TemperatureHistory::new(TEMPERATURE_HISTORY_WINDOW));
+    if let Some(path) = TEMPERATURE_HISTORY_FILE {
+        match history.restore(Path::new(path)) {
+            Ok(restored) => info!("Restored {restored} temperature samples from {path}"),
+            Err(e) => warn!("Starting with an empty temperature history: {e:#}"),
         }
-    };
+    }
 
     // Create status watch channel
     let (status_sender, status_receiver) = create_status_watch();
+    // Lifecycle events detected by the keep-alive loop
+    let (event_sender, _) = create_event_channel();
 
     // Initialize Tera templates
     let tera = match Tera::new("templates/**/*") {
@@ -43,35 +111,187 @@ async fn main() -> anyhow::Result<()> {
 
     // Create app state with both upload and status functionality
     let app_state = web::Data::new(AppState {
-        snapmaker_token: token.clone(),
+        pairing: pairing.clone(),
         status_watch: status_receiver,
         tera: tera.clone(),
+        audit: Arc::new(AuditLog::new(AUDIT_LOG_FILE)),
+        interlock: Arc::new(Interlock::load(STOP_CONFIRM_WINDOW, LOCK_FILE)),
+        console: Arc::new(Console::new(CONSOLE_SCROLLBACK)),
+        history: history.clone(),
+        presets: Arc::new(Presets::load(PRESETS_FILE, DEFAULT_PRESETS)),
+        read_only: READ_ONLY,
+        last_upload: Arc::new(Mutex::new(None)),
+        temperature_offsets: Arc::new(Mutex::new(HashMap::new())),
+    });
+    let observer_state = web::Data::new(AppState {
+        read_only: true,
+        ..app_state.get_ref().clone()
     });
 
-    info!("Starting server on {}", SERVE_ADDRESS);
+    // Load the certificate before anything is bound, so a bad TLS setup fails early
+    let tls_config = match TLS_ADDRESS {
+        Some(_) => Some(tls::load_server_config()?),
+        None => None,
+    };
+    let redirect_http = TLS_ADDRESS.is_some() && TLS_REDIRECT_HTTP;
+
+    // Publish to MQTT, commands are only taken when we may control the printer
+    let mqtt_control = (!READ_ONLY).then(|| MqttControl {
+        pairing: pairing.clone(),
+        interlock: app_state.interlock.clone(),
+        audit: app_state.audit.clone(),
+        stop_token: Arc::default(),
+    });
+    tokio::spawn(mqtt_loop(
+        app_state.status_watch.clone(),
+        event_sender.subscribe(),
+        mqtt_control,
+    ));
+    tokio::spawn(webhook_loop(event_sender.subscribe()));
+    // The watchdog acts without the interlock, a locked printer still needs protecting
+    let watchdog_control = (!READ_ONLY).then(|| WatchdogControl {
+        pairing: pairing.clone(),
+        audit: app_state.audit.clone(),
+    });
+    tokio::spawn(thermal_watchdog_loop(
+        app_state.status_watch.clone(),
+        event_sender.clone(),
+        watchdog_control,
+    ));
+    tokio::spawn(systemd::systemd_loop(app_state.status_watch.clone()));
+    tokio::spawn(email_loop(
+        event_sender.subscribe(),
+        app_state.last_upload.clone(),
+    ));
 
-    // Spawn keepalive thread with status sender
-    tokio::spawn(keep_alive_loop(token, status_sender));
+    // Spawn keepalive thread with status sender, it starts polling once paired
+    let _ = status_sender.send(PrinterStatus::unavailable("Printer not paired"));
+    let keep_alive = tokio::spawn({
+        let pairing = pairing.clone();
+        let history = history.clone();
+        async move {
+            loop {
+                // until paired, the discovery reply is all anyone gets from the printer
+                let token = tokio::select! {
+                    token = pairing.paired() => token,
+                    _ = discovery::announced_status_loop(&status_sender) => return Ok(()),
+                };
+                let result = keep_alive_loop(token, &status_sender, &event_sender, &history).await;
+                // no more polls to wait for, pairing again may take a while
+                systemd::clear_heartbeat();
+                match result {
+                    Err(e) if Unauthorized::is(&e) => pairing.revoke(),
+                    result => return result,
+                }
+            }
+        }
+    });
 
-    HttpServer::new(move || {
+    let configure = if READ_ONLY {
+        http_endpoints::configure_read_only
+    } else {
+        http_endpoints::configure
+    };
+    // With socket activation systemd already holds SERVE_ADDRESS for us
+    let mut systemd_listeners = systemd::listeners();
+    // Signals are handled in shutdown_on_signal, uploads get to finish first
+    let mut server = HttpServer::new(move || {
         App::new()
+            .wrap_fn(|req, srv| {
+                // scrapers and health probes don't count as someone watching
+                if !matches!(req.path(), "/metrics" | "/healthz" | "/readyz") {
+                    activity().request();
+                }
+                srv.call(req)
+            })
             .wrap(Logger::default())
             .app_data(app_state.clone())
-            .service(http_endpoints::handle_upload)
-            .service(http_endpoints::get_version)
-            .service(http_endpoints::get_status)
-            .service(http_endpoints::get_rendered_status)
-            .service(http_endpoints::get_rendered_controls)
-            .service(http_endpoints::get_index)
-            .service(http_endpoints::set_enclosure_light)
-            .service(http_endpoints::set_enclosure_fan)
-            .service(http_endpoints::pause_print)
-            .service(http_endpoints::stop_print)
-            .service(http_endpoints::resume_print)
-            .service(actix_files::Files::new("/static", "static").show_files_listing())
+            .configure(configure)
     })
-    .bind(SERVE_ADDRESS)?
-    .run()
-    .await
-    .map_err(|e| anyhow::anyhow!(e))
+    .disable_signals();
+    if !redirect_http {
+        if systemd_listeners.is_empty() {
+            info!("Starting server on {}", SERVE_ADDRESS);
+            server = server.bind(SERVE_ADDRESS)?;
+        }
+        for listener in systemd_listeners.drain(..) {
+            server = server.listen(listener)?;
+        }
+    }
+    if let (Some(address), Some(tls_config)) = (TLS_ADDRESS, tls_config) {
+        info!("Starting TLS server on {}", address);
+        server = server.bind_rustls_0_23(address, tls_config)?;
+    }
+    let mut servers = vec![server.run()];
+
+    if redirect_http {
+        info!("Redirecting plain HTTP on {} to HTTPS", SERVE_ADDRESS);
+        let mut redirect_server = HttpServer::new(|| {
+            App::new()
+                .wrap(Logger::default())
+                .default_service(web::to(tls::redirect_to_https))
+        })
+        .disable_signals();
+        if systemd_listeners.is_empty() {
+            redirect_server = redirect_server.bind(SERVE_ADDRESS)?;
+        }
+        for listener in systemd_listeners.drain(..) {
+            redirect_server = redirect_server.listen(listener)?;
+        }
+        servers.push(redirect_server.run());
+    }
+
+    if let Some(address) = OBSERVER_ADDRESS {
+        info!("Starting read-only observer server on {}", address);
+        let observer_server = HttpServer::new(move || {
+            App::new()
+                .wrap_fn(|req, srv| {
+                    // scrapers and health probes don't count as someone watching
+                    if !matches!(req.path(), "/metrics" | "/healthz" | "/readyz") {
+                        activity().request();
+                    }
+                    srv.call(req)
+                })
+                .wrap(Logger::default())
+                .app_data(observer_state.clone())
+                .configure(http_endpoints::configure_read_only)
+        })
+        .disable_signals()
+        .bind(address)?;
+        servers.push(observer_server.run());
+    }
+
+    // Every address bound, systemd may start what depends on us once we're paired
+    tokio::spawn(async move {
+        // an observer serves what the printer announces right away
+        if !READ_ONLY {
+            pairing.paired().await;
+        }
+        systemd::notify("READY=1");
+    });
+    let handles = servers.iter().map(|x| x.handle()).collect();
+    tokio::spawn(shutdown_on_signal(handles, UPLOAD_DRAIN_TIMEOUT));
+    futures::future::try_join_all(servers)
+        .await
+        .map_err(|e| anyhow::anyhow!(e))?;
+
+    // The servers are down, let the keep-alive loop finish its poll before saving
+    if tokio::time::timeout(Duration::from_secs(10), keep_alive)
+        .await
+        .is_err()
+    {
+        warn!("Keep-alive didn't stop in time");
+    }
+    // slicers drop the proxy from their lists on the mDNS goodbye
+    if let Some(mdns) = mdns {
+        let _ = tokio::time::timeout(Duration::from_secs(2), mdns).await;
+    }
+    if let Some(path) = TEMPERATURE_HISTORY_FILE {
+        match history.save(Path::new(path)) {
+            Ok(_) => info!("Saved the temperature history to {path}"),
+            Err(e) => warn!("Failed to save the temperature history: {e:#}"),
+        }
+    }
+    info!("Stopped");
+    Ok(())
 }
@@ -0,0 +1,143 @@
+use std::{fs, net::SocketAddr, sync::Arc};
+
+use log::{info, warn};
+use mdns_sd::{IfKind, ServiceDaemon, ServiceInfo};
+
+use crate::{
+    config::{MDNS_NAME, PRINTER_NAME, SERVE_ADDRESS},
+    http_endpoints::{OCTOPRINT_API_VERSION, OCTOPRINT_SERVER_VERSION},
+    pairing::Pairing,
+    shutdown::{Phase, shutdown},
+    snapmaker_client::printer_info,
+};
+
+/// What OctoPrint's zeroconf plugin announces, and slicers browse for
+const SERVICE_TYPE: &str = "_octoprint._tcp.local.";
+
+/// Announces the proxy as an OctoPrint instance once paired, the model is known
+/// by then, and says goodbye on shutdown
+pub(crate) async fn mdns_loop(pairing: Arc<Pairing>) {
+    tokio::select! {
+        _ = pairing.paired() => (),
+        _ = shutdown().reached(Phase::Stopping) => return,
+    }
+    let advertised = SERVE_ADDRESS
+        .parse()
+        .map_err(anyhow::Error::from)
+        .and_then(|address| advertise(address, &instance_name()));
+    let (daemon, fullname) = match advertised {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Failed to advertise over mDNS: {e:#}");
+            return;
+        }
+    };
+    shutdown().reached(Phase::Stopping).await;
+    // unregistering sends the goodbye packets, waiting for them keeps the daemon alive
+    if let Ok(receiver) = daemon.unregister(&fullname) {
+        let _ = receiver.recv_async().await;
+    }
+    let _ = daemon.shutdown();
+}
+
+/// The running daemon and the full name it announces for the listener at `address`
+fn advertise(address: SocketAddr, name: &str) -> anyhow::Result<(ServiceDaemon, String)> {
+    let daemon = ServiceDaemon::new()?;
+    let model = printer_info().model;
+    let properties = [
+        ("path", "/"),
+        ("api", OCTOPRINT_API_VERSION),
+        ("version", OCTOPRINT_SERVER_VERSION),
+        ("model", model.as_deref().unwrap_or("Snapmaker 2.0")),
+        ("vendor", "Snapmaker"),
+    ];
+    let host = format!("{}.local.", hostname());
+    let service = if address.ip().is_unspecified() {
+        // listening everywhere, announce every address the host has
+        ServiceInfo::new(SERVICE_TYPE, name, &host, (), address.port(), &properties[..])?
+            .enable_addr_auto()
+    } else {
+        // mdns-sd leaves loopback out, yet that's the only place a 127.0.0.1
+        // listener can be reached from (and announced on)
+        if address.ip().is_loopback() {
+            daemon.enable_interface(IfKind::LoopbackV4)?;
+        }
+        ServiceInfo::new(SERVICE_TYPE, name, &host, address.ip(), address.port(), &properties[..])?
+    };
+    let fullname = service.get_fullname().to_string();
+    daemon.register(service)?;
+    info!("Advertising \"{name}\" over mDNS on port {}", address.port());
+    Ok((daemon, fullname))
+}
+
+/// `MDNS_NAME`, or the model like "Snapmaker A350 (proxy)"
+fn instance_name() -> String {
+    if let Some(name) = MDNS_NAME {
+        return name.to_string();
+    }
+    // the printer calls itself e.g. "Snapmaker 2.0 A350", the size tells printers apart
+    match printer_info().model.as_deref().and_then(|x| x.split_whitespace().last()) {
+        Some(size) => format!("Snapmaker {size} (proxy)"),
+        None => format!("{PRINTER_NAME} (proxy)"),
+    }
+}
+
+fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|x| x.trim().to_string())
+        .ok()
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| "sm-proxy".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use mdns_sd::ServiceEvent;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn announces_on_loopback() {
+        let name = format!("sm-proxy test {}", std::process::id());
+        let (daemon, fullname) = advertise("127.0.0.1:55599".parse().unwrap(), &name).unwrap();
+        assert_eq!(fullname, format!("{name}.{SERVICE_TYPE}"));
+
+        let browser = ServiceDaemon::new().unwrap();
+        browser.enable_interface(IfKind::LoopbackV4).unwrap();
+        let events = browser.browse(SERVICE_TYPE).unwrap();
+        let resolved = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let ServiceEvent::ServiceResolved(info) = events.recv_async().await.unwrap()
+                    && info.get_fullname() == fullname
+                {
+                    return info;
+                }
+            }
+        })
+        .await
+        .expect("the announcement never arrived");
+        assert_eq!(resolved.get_port(), 55599);
+        assert!(resolved.get_addresses().contains(&"127.0.0.1".parse().unwrap()));
+        assert_eq!(resolved.get_property_val_str("path"), Some("/"));
+        assert_eq!(resolved.get_property_val_str("api"), Some(OCTOPRINT_API_VERSION));
+        assert_eq!(resolved.get_property_val_str("vendor"), Some("Snapmaker"));
+
+        // the goodbye reaches browsers
+        daemon.unregister(&fullname).unwrap().recv_async().await.unwrap();
+        let removed = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let ServiceEvent::ServiceRemoved(_, removed) = events.recv_async().await.unwrap()
+                    && removed == fullname
+                {
+                    return;
+                }
+            }
+        })
+        .await;
+        assert!(removed.is_ok(), "no goodbye for {fullname}");
+        let _ = daemon.shutdown();
+        let _ = browser.shutdown();
+    }
+}
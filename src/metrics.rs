@@ -0,0 +1,441 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    config::PRINTER_NAME,
+    scheduler::scheduler,
+    status::{ConnectionState, PrinterStatus},
+};
+
+const LATENCY_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+/// Proxy-internal counters, printer values come straight from the status watch
+#[derive(Default)]
+pub struct Metrics {
+    keep_alive_success: AtomicU64,
+    keep_alive_failure: AtomicU64,
+    uploads: AtomicU64,
+    upload_bytes: AtomicU64,
+    commands: Mutex<BTreeMap<&'static str, u64>>,
+    request_latency: Mutex<BTreeMap<&'static str, Histogram>>,
+    request_outcomes: Mutex<BTreeMap<&'static str, RequestOutcome>>,
+}
+
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+    max: f64,
+    last: f64,
+}
+
+#[derive(Default)]
+struct RequestOutcome {
+    last_success: Option<DateTime<Utc>>,
+    last_error: Option<RequestError>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RequestError {
+    pub timestamp: DateTime<Utc>,
+    pub message: String,
+}
+
+/// Latency and last outcome of one kind of printer request
+#[derive(Debug, Serialize)]
+pub struct RequestDiagnostics {
+    pub count: u64,
+    pub mean_seconds: f64,
+    pub max_seconds: f64,
+    pub last_seconds: f64,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_error: Option<RequestError>,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            buckets: vec![0; LATENCY_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+            max: 0.0,
+            last: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+        self.max = self.max.max(value);
+        self.last = value;
+    }
+}
+
+impl Metrics {
+    pub fn keep_alive(&self, success: bool) {
+        let counter = if success {
+            &self.keep_alive_success
+        } else {
+            &self.keep_alive_failure
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn upload(&self, bytes: usize) {
+        self.uploads.fetch_add(1, Ordering::Relaxed);
+        self.upload_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn command(&self, kind: &'static str) {
+        let mut commands = self.commands.lock().unwrap_or_else(|e| e.into_inner());
+        *commands.entry(kind).or_default() += 1;
+    }
+
+    /// `error` is `None` when the printer answered with success
+    pub fn printer_request(&self, kind: &'static str, duration: Duration, error: Option<String>) {
+        let mut latency = self
+            .request_latency
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        latency
+            .entry(kind)
+            .or_insert_with(Histogram::new)
+            .observe(duration.as_secs_f64());
+        drop(latency);
+
+        let mut outcomes = self
+            .request_outcomes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let outcome = outcomes.entry(kind).or_default();
+        match error {
+            None => outcome.last_success = Some(Utc::now()),
+            Some(message) => {
+                outcome.last_error = Some(RequestError {
+                    timestamp: Utc::now(),
+                    message: redact_token(&message),
+                })
+            }
+        }
+    }
+
+    /// Per request kind, for the diagnostics endpoint
+    pub fn request_diagnostics(&self) -> BTreeMap<&'static str, RequestDiagnostics> {
+        let latency = self
+            .request_latency
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let outcomes = self
+            .request_outcomes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        latency
+            .iter()
+            .map(|(kind, histogram)| {
+                let outcome = outcomes.get(kind);
+                let diagnostics = RequestDiagnostics {
+                    count: histogram.count,
+                    mean_seconds: histogram.sum / histogram.count.max(1) as f64,
+                    max_seconds: histogram.max,
+                    last_seconds: histogram.last,
+                    last_success: outcome.and_then(|x| x.last_success),
+                    last_error: outcome.and_then(|x| x.last_error.clone()),
+                };
+                (*kind, diagnostics)
+            })
+            .collect()
+    }
+
+    /// Prometheus text exposition format
+    pub fn render(&self, status: &PrinterStatus) -> String {
+        let printer = format!("printer=\"{}\"", escape_label(PRINTER_NAME));
+        let mut out = String::new();
+
+        // without live readings the gauges would be zeros or old values, leave them out
+        let up = status.has_readings() && status.connection != ConnectionState::Offline;
+        header(
+            &mut out,
+            "sm_printer_up",
+            "1 while the printer answers and its readings are current",
+            "gauge",
+        );
+        let _ = writeln!(out, "sm_printer_up{{{printer}}} {}", u8::from(up));
+        if up {
+            render_printer(&mut out, &printer, status);
+        }
+        self.render_proxy(&mut out, &printer);
+        out
+    }
+
+    fn render_proxy(&self, out: &mut String, printer: &str) {
+        header(
+            out,
+            "sm_proxy_keep_alive_total",
+            "Keep-alive polls by result",
+            "counter",
+        );
+        for (result, counter) in [
+            ("success", &self.keep_alive_success),
+            ("failure", &self.keep_alive_failure),
+        ] {
+            let _ = writeln!(
+                out,
+                "sm_proxy_keep_alive_total{{{printer},result=\"{result}\"}} {}",
+                counter.load(Ordering::Relaxed)
+            );
+        }
+
+        header(
+            out,
+            "sm_proxy_uploads_total",
+            "Files uploaded to the printer",
+            "counter",
+        );
+        let _ = writeln!(
+            out,
+            "sm_proxy_uploads_total{{{printer}}} {}",
+            self.uploads.load(Ordering::Relaxed)
+        );
+        header(
+            out,
+            "sm_proxy_upload_bytes_total",
+            "Bytes uploaded to the printer",
+            "counter",
+        );
+        let _ = writeln!(
+            out,
+            "sm_proxy_upload_bytes_total{{{printer}}} {}",
+            self.upload_bytes.load(Ordering::Relaxed)
+        );
+
+        header(
+            out,
+            "sm_proxy_commands_total",
+            "Commands sent to the printer by type",
+            "counter",
+        );
+        for (kind, count) in self
+            .commands
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+        {
+            let _ = writeln!(
+                out,
+                "sm_proxy_commands_total{{{printer},command=\"{kind}\"}} {count}"
+            );
+        }
+
+        let name = "sm_proxy_printer_request_duration_seconds";
+        header(out, name, "Latency of requests to the printer", "histogram");
+        for (kind, histogram) in self
+            .request_latency
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+        {
+            let labels = format!("{printer},request=\"{kind}\"");
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+                let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {count}");
+            }
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{labels},le=\"+Inf\"}} {}",
+                histogram.count
+            );
+            let _ = writeln!(out, "{name}_sum{{{labels}}} {}", histogram.sum);
+            let _ = writeln!(out, "{name}_count{{{labels}}} {}", histogram.count);
+        }
+
+        let stats = scheduler().stats();
+        let scheduler_values = [
+            (
+                "sm_proxy_scheduler_queue_depth",
+                "Printer requests waiting for a slot",
+                "gauge",
+                stats.queue_depth as u64,
+            ),
+            (
+                "sm_proxy_scheduler_in_flight",
+                "Printer requests currently running",
+                "gauge",
+                stats.in_flight as u64,
+            ),
+            (
+                "sm_proxy_scheduler_coalesced_reads_total",
+                "Reads answered by a request already in flight",
+                "counter",
+                stats.coalesced_reads,
+            ),
+            (
+                "sm_proxy_scheduler_rate_limited_total",
+                "Commands rejected by the rate limit",
+                "counter",
+                stats.rate_limited,
+            ),
+        ];
+        for (name, help, kind, value) in scheduler_values {
+            header(out, name, help, kind);
+            let _ = writeln!(out, "{name}{{{printer}}} {value}");
+        }
+    }
+}
+
+/// Readings of the printer, only current ones are worth a gauge
+fn render_printer(out: &mut String, printer: &str, status: &PrinterStatus) {
+    let gauges = [
+        (
+            "sm_printer_nozzle_temperature_celsius",
+            "Nozzle temperature",
+            status.nozzle_temperature,
+        ),
+        (
+            "sm_printer_nozzle_target_temperature_celsius",
+            "Nozzle target temperature",
+            status.nozzle_target_temperature,
+        ),
+        (
+            "sm_printer_bed_temperature_celsius",
+            "Heated bed temperature",
+            status.heated_bed_temperature,
+        ),
+        (
+            "sm_printer_bed_target_temperature_celsius",
+            "Heated bed target temperature",
+            status.heated_bed_target_temperature,
+        ),
+        (
+            "sm_printer_progress_ratio",
+            "Print progress from 0 to 1",
+            status.progress,
+        ),
+        (
+            "sm_printer_remaining_time_seconds",
+            "Estimated remaining print time",
+            status.remaining_time,
+        ),
+        (
+            "sm_printer_work_speed_mm_per_minute",
+            "Current work speed",
+            status.work_speed,
+        ),
+        (
+            "sm_printer_enclosure_led_percent",
+            "Enclosure light intensity",
+            status.enclosure.led as f64,
+        ),
+        (
+            "sm_printer_enclosure_fan_percent",
+            "Enclosure fan speed",
+            status.enclosure.fan as f64,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        header(out, name, help, "gauge");
+        let _ = writeln!(out, "{name}{{{printer}}} {value}");
+    }
+
+    header(out, "sm_printer_position_mm", "Toolhead position", "gauge");
+    for (axis, value) in [("x", status.x), ("y", status.y), ("z", status.z)] {
+        let _ = writeln!(
+            out,
+            "sm_printer_position_mm{{{printer},axis=\"{axis}\"}} {value}"
+        );
+    }
+
+    header(
+        out,
+        "sm_printer_state",
+        "Printer state reported by the Snapmaker",
+        "gauge",
+    );
+    let _ = writeln!(
+        out,
+        "sm_printer_state{{{printer},state=\"{}\"}} 1",
+        escape_label(&status.status)
+    );
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Request errors quote the URL, which carries the token as a query parameter
+fn redact_token(message: &str) -> String {
+    let mut out = String::with_capacity(message.len());
+    let mut rest = message;
+    while let Some(start) = rest.find("token=") {
+        let value = &rest[start + "token=".len()..];
+        let end = value
+            .find(|x: char| matches!(x, '&' | ')' | '"' | '\'') || x.is_whitespace())
+            .unwrap_or(value.len());
+        out.push_str(&rest[..start]);
+        out.push_str("token=<redacted>");
+        rest = &value[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn printer_gauges_only_with_live_readings() {
+        let metrics = Metrics::default();
+        let live = PrinterStatus {
+            status: "RUNNING".to_string(),
+            nozzle_temperature: 205.0,
+            connection: ConnectionState::Connected,
+            ..Default::default()
+        };
+        let out = metrics.render(&live);
+        assert!(out.contains("sm_printer_up{printer=\"snapmaker\"} 1\n"));
+        assert!(out.contains("sm_printer_nozzle_temperature_celsius{printer=\"snapmaker\"} 205\n"));
+        assert!(out.contains("sm_printer_state{printer=\"snapmaker\",state=\"RUNNING\"} 1\n"));
+
+        let stale = PrinterStatus {
+            stale: true,
+            connection: ConnectionState::Degraded,
+            ..live.clone()
+        };
+        let unpaired = PrinterStatus::announced("IDLE", None);
+        for status in [stale, unpaired, PrinterStatus::offline(None)] {
+            let out = metrics.render(&status);
+            assert!(out.contains("sm_printer_up{printer=\"snapmaker\"} 0\n"), "{status:?}");
+            assert!(!out.contains("sm_printer_nozzle"), "{status:?}");
+            assert!(!out.contains("sm_printer_state"), "{status:?}");
+            assert!(out.contains("sm_proxy_keep_alive_total"), "{status:?}");
+        }
+    }
+}
@@ -0,0 +1,613 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{error, info, warn};
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::{Value, json};
+use tokio::sync::{broadcast, watch};
+
+use crate::{
+    activity::activity,
+    audit::AuditLog,
+    config::{
+        MQTT_BROKER, MQTT_DISCOVERY_PREFIX, MQTT_PASSWORD, MQTT_TOPIC_PREFIX, MQTT_USERNAME,
+        PRINTER_NAME,
+    },
+    events::PrinterEvent,
+    interlock::{Command, Confirmation, Interlock, Refusal},
+    pairing::Pairing,
+    scheduler::scheduler,
+    snapmaker_client,
+    status::PrinterStatus,
+};
+
+/// Everything MQTT commands need to act on the printer like the HTTP endpoints do
+#[derive(Clone)]
+pub(crate) struct MqttControl {
+    pub pairing: Arc<Pairing>,
+    pub interlock: Arc<Interlock>,
+    pub audit: Arc<AuditLog>,
+    /// Armed by a first `stop`, a second one within the confirmation window stops
+    pub stop_token: Arc<Mutex<Option<String>>>,
+}
+
+struct Topics {
+    base: String,
+    node_id: String,
+}
+
+impl Topics {
+    fn new() -> Self {
+        let node_id: String = PRINTER_NAME
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        Self {
+            base: format!("{MQTT_TOPIC_PREFIX}/{node_id}"),
+            node_id,
+        }
+    }
+
+    fn topic(&self, name: &str) -> String {
+        format!("{}/{name}", self.base)
+    }
+}
+
+/// Publishes the printer status to MQTT, `control` is `None` for observers
+pub(crate) async fn mqtt_loop(
+    status_watch: watch::Receiver<PrinterStatus>,
+    events: broadcast::Receiver<PrinterEvent>,
+    control: Option<MqttControl>,
+) {
+    let Some((host, port)) = MQTT_BROKER else {
+        return;
+    };
+    run(host, port, status_watch, events, control).await
+}
+
+async fn run(
+    host: &str,
+    port: u16,
+    status_watch: watch::Receiver<PrinterStatus>,
+    events: broadcast::Receiver<PrinterEvent>,
+    control: Option<MqttControl>,
+) {
+    let topics = Arc::new(Topics::new());
+
+    let mut options = MqttOptions::new(format!("sm-proxy-{}", topics.node_id), host, port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(
+        topics.topic("availability"),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let (Some(username), Some(password)) = (MQTT_USERNAME, MQTT_PASSWORD) {
+        options.set_credentials(username, password);
+    }
+    let (client, mut event_loop) = AsyncClient::new(options, 64);
+
+    tokio::spawn(publish_status(
+        client.clone(),
+        topics.clone(),
+        status_watch.clone(),
+    ));
+    tokio::spawn(publish_events(client.clone(), topics.clone(), events));
+
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker {host}:{port}");
+                activity().set_push_connected(true);
+                tokio::spawn(announce(client.clone(), topics.clone(), control.is_some()));
+            }
+            // a retained command would be replayed on every reconnect
+            Ok(Event::Incoming(Packet::Publish(publish))) if publish.retain => {
+                warn!("Ignoring retained MQTT message on {}", publish.topic);
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                if let Some(control) = control.clone() {
+                    let payload = String::from_utf8_lossy(&publish.payload).trim().to_string();
+                    let topics = topics.clone();
+                    let status_watch = status_watch.clone();
+                    tokio::spawn(async move {
+                        handle_command(&topics, &publish.topic, &payload, &control, &status_watch)
+                            .await
+                    });
+                }
+            }
+            Ok(_) => (),
+            Err(e) => {
+                // the event loop reconnects on the next poll
+                warn!("MQTT connection error: {e}");
+                activity().set_push_connected(false);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+async fn announce(client: AsyncClient, topics: Arc<Topics>, with_controls: bool) {
+    let mut messages = discovery_payloads(&topics, with_controls);
+    messages.push((topics.topic("availability"), json!("online")));
+    for (topic, payload) in messages {
+        let payload = match payload {
+            Value::String(x) => x,
+            x => x.to_string(),
+        };
+        if let Err(e) = client.publish(topic, QoS::AtLeastOnce, true, payload).await {
+            error!("Failed to publish MQTT discovery: {e}");
+            return;
+        }
+    }
+    if with_controls {
+        for name in ["light/set", "fan/set", "command"] {
+            if let Err(e) = client.subscribe(topics.topic(name), QoS::AtLeastOnce).await {
+                error!("Failed to subscribe to MQTT commands: {e}");
+            }
+        }
+    }
+}
+
+async fn publish_status(
+    client: AsyncClient,
+    topics: Arc<Topics>,
+    mut status_watch: watch::Receiver<PrinterStatus>,
+) {
+    let mut published: HashMap<String, String> = HashMap::new();
+
+    while status_watch.changed().await.is_ok() {
+        let status = status_watch.borrow_and_update().clone();
+        let Ok(Value::Object(fields)) = serde_json::to_value(&status) else {
+            continue;
+        };
+
+        let mut messages = vec![(
+            topics.topic("state"),
+            Value::Object(fields.clone()).to_string(),
+        )];
+        for (field, value) in fields {
+            let value = match value {
+                Value::String(x) => x,
+                x => x.to_string(),
+            };
+            // per-field topics are retained, only send what changed
+            if published.get(&field) != Some(&value) {
+                messages.push((topics.topic(&format!("status/{field}")), value.clone()));
+                published.insert(field, value);
+            }
+        }
+        for (topic, payload) in messages {
+            if let Err(e) = client.publish(topic, QoS::AtMostOnce, true, payload).await {
+                error!("Failed to publish MQTT status: {e}");
+            }
+        }
+    }
+}
+
+async fn publish_events(
+    client: AsyncClient,
+    topics: Arc<Topics>,
+    mut events: broadcast::Receiver<PrinterEvent>,
+) {
+    loop {
+        let event = match events.recv().await {
+            Ok(x) => x,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let payload = json!({
+            "event": event.kind,
+            "title": event.kind.title(),
+            "message": event.message,
+            "timestamp": event.timestamp,
+            "file_name": event.status.file_name,
+        });
+        let topic = topics.topic("event");
+        if let Err(e) = client
+            .publish(topic, QoS::AtLeastOnce, false, payload.to_string())
+            .await
+        {
+            error!("Failed to publish MQTT event: {e}");
+        }
+    }
+}
+
+async fn handle_command(
+    topics: &Topics,
+    topic: &str,
+    payload: &str,
+    control: &MqttControl,
+    status_watch: &watch::Receiver<PrinterStatus>,
+) {
+    let name = topic
+        .strip_prefix(&format!("{}/", topics.base))
+        .unwrap_or(topic);
+    let (action, command, parameters) = match (name, payload.to_ascii_lowercase().as_str()) {
+        ("light/set", "on") => (
+            "set_enclosure_light",
+            Command::Enclosure,
+            json!({ "value": 100 }),
+        ),
+        ("light/set", "off") => (
+            "set_enclosure_light",
+            Command::Enclosure,
+            json!({ "value": 0 }),
+        ),
+        ("fan/set", value) => match value.parse::<f64>() {
+            Ok(x) => (
+                "set_enclosure_fan",
+                Command::Enclosure,
+                json!({ "value": x.clamp(0.0, 100.0).round() as u8 }),
+            ),
+            Err(_) => {
+                warn!("Ignoring MQTT fan value {payload:?}");
+                return;
+            }
+        },
+        ("command", "pause") => ("pause_print", Command::Pause, json!({})),
+        ("command", "resume") => ("resume_print", Command::Resume, json!({})),
+        ("command", "stop") => ("stop_print", Command::Stop, json!({})),
+        _ => {
+            warn!("Ignoring MQTT command {payload:?} on {topic}");
+            return;
+        }
+    };
+
+    let source = "mqtt".to_string();
+    if !scheduler().allow_command(&source) {
+        let message = "Too many commands, slow down";
+        control
+            .audit
+            .record_from(source, None, action, parameters, &Err::<(), _>(message));
+        return;
+    }
+    // a stop needs a second press within the window, like arming over HTTP
+    let confirmation = Confirmation {
+        confirm: false,
+        token: match command {
+            Command::Stop => control.stop_token.lock().unwrap_or_else(|e| e.into_inner()).take(),
+            _ => None,
+        },
+    };
+    let status = status_watch.borrow().clone();
+    if let Err(refusal) = control.interlock.check(command, &status, &confirmation) {
+        if refusal == Refusal::ConfirmationRequired {
+            *control.stop_token.lock().unwrap_or_else(|e| e.into_inner()) =
+                Some(control.interlock.arm());
+            let window = control.interlock.window().as_secs();
+            warn!("MQTT stop armed, send stop again within {window}s to stop the print");
+        } else {
+            warn!("Refused MQTT command {action}: {refusal}");
+        }
+        control
+            .audit
+            .record_from(source, None, action, parameters, &Err::<(), _>(&refusal));
+        return;
+    }
+
+    let Some(token) = control.pairing.token() else {
+        let message = "Not paired with the printer yet";
+        warn!("Refused MQTT command {action}: {message}");
+        control
+            .audit
+            .record_from(source, None, action, parameters, &Err::<(), _>(message));
+        return;
+    };
+    let token = &token;
+    let value = parameters["value"].as_u64().unwrap_or_default() as u8;
+    let result = match action {
+        "set_enclosure_light" => snapmaker_client::set_enclosure_light(token, value).await,
+        "set_enclosure_fan" => snapmaker_client::set_enclosure_fan(token, value).await,
+        "pause_print" => snapmaker_client::pause_print(token).await,
+        "resume_print" => snapmaker_client::resume_print(token).await,
+        _ => snapmaker_client::stop_print(token).await,
+    };
+    if let Err(e) = &result {
+        error!("MQTT command {action} failed: {e:?}");
+    }
+    control
+        .audit
+        .record_from(source, None, action, parameters, &result);
+}
+
+/// Home Assistant MQTT discovery configs, see
+/// https://www.home-assistant.io/integrations/mqtt/#mqtt-discovery
+fn discovery_payloads(topics: &Topics, with_controls: bool) -> Vec<(String, Value)> {
+    let node_id = &topics.node_id;
+    let device = json!({
+        "identifiers": [node_id],
+        "name": PRINTER_NAME,
+        "manufacturer": "Snapmaker",
+        "model": "Snapmaker 2.0",
+    });
+    let availability = topics.topic("availability");
+    let state = topics.topic("state");
+    let entity = |component: &str, object_id: &str, name: &str, extra: Value| {
+        let mut config = json!({
+            "name": name,
+            "unique_id": format!("{node_id}_{object_id}"),
+            "object_id": format!("{node_id}_{object_id}"),
+            "device": device,
+            "availability_topic": availability,
+        });
+        if let (Value::Object(config), Value::Object(extra)) = (&mut config, extra) {
+            config.extend(extra);
+        }
+        (
+            format!("{MQTT_DISCOVERY_PREFIX}/{component}/{node_id}/{object_id}/config"),
+            config,
+        )
+    };
+    let temperature = |object_id: &str, name: &str, field: &str| {
+        entity(
+            "sensor",
+            object_id,
+            name,
+            json!({
+                "state_topic": state,
+                "value_template": format!("{{{{ value_json.{field} }}}}"),
+                "unit_of_measurement": "°C",
+                "device_class": "temperature",
+                "state_class": "measurement",
+            }),
+        )
+    };
+
+    let mut payloads = vec![
+        temperature(
+            "nozzle_temperature",
+            "Nozzle temperature",
+            "nozzle_temperature",
+        ),
+        temperature(
+            "nozzle_target",
+            "Nozzle target",
+            "nozzle_target_temperature",
+        ),
+        temperature(
+            "bed_temperature",
+            "Bed temperature",
+            "heated_bed_temperature",
+        ),
+        temperature("bed_target", "Bed target", "heated_bed_target_temperature"),
+        entity(
+            "sensor",
+            "progress",
+            "Progress",
+            json!({
+                "state_topic": state,
+                "value_template": "{{ (value_json.progress * 100) | round(1) }}",
+                "unit_of_measurement": "%",
+            }),
+        ),
+        entity(
+            "sensor",
+            "remaining_time",
+            "Remaining time",
+            json!({
+                "state_topic": state,
+                "value_template": "{{ value_json.remaining_time | int }}",
+                "unit_of_measurement": "s",
+                "device_class": "duration",
+            }),
+        ),
+        entity(
+            "sensor",
+            "print_status",
+            "Print status",
+            json!({
+                "state_topic": state,
+                "value_template": "{{ value_json.print_status }}",
+            }),
+        ),
+    ];
+    if with_controls {
+        payloads.push(entity(
+            "switch",
+            "enclosure_light",
+            "Enclosure light",
+            json!({
+                "state_topic": state,
+                "value_template": "{{ 'ON' if value_json.enclosure.led > 0 else 'OFF' }}",
+                "command_topic": topics.topic("light/set"),
+                "payload_on": "ON",
+                "payload_off": "OFF",
+            }),
+        ));
+        payloads.push(entity(
+            "number",
+            "enclosure_fan",
+            "Enclosure fan",
+            json!({
+                "state_topic": state,
+                "value_template": "{{ value_json.enclosure.fan }}",
+                "command_topic": topics.topic("fan/set"),
+                "min": 0,
+                "max": 100,
+                "step": 1,
+                "unit_of_measurement": "%",
+            }),
+        ));
+        for (action, name) in [
+            ("pause", "Pause print"),
+            ("resume", "Resume print"),
+            ("stop", "Stop print"),
+        ] {
+            payloads.push(entity(
+                "button",
+                action,
+                name,
+                json!({
+                    "command_topic": topics.topic("command"),
+                    "payload_press": action,
+                }),
+            ));
+        }
+    }
+    payloads
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use rumqttc::{ConnAck, ConnectReturnCode, PubAck, Publish, SubAck, SubscribeReasonCode};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{events::create_event_channel, status::create_status_watch};
+
+    /// Just enough of a broker for one client: acknowledges everything and
+    /// lets the test publish to it
+    struct Broker {
+        stream: TcpStream,
+        buffer: BytesMut,
+    }
+
+    impl Broker {
+        async fn recv(&mut self) -> Packet {
+            loop {
+                match rumqttc::read(&mut self.buffer, 1 << 20) {
+                    Ok(packet) => return self.acknowledge(packet).await,
+                    Err(rumqttc::Error::InsufficientBytes(_)) => (),
+                    Err(e) => panic!("Broken packet from the client: {e:?}"),
+                }
+                let read = tokio::time::timeout(
+                    Duration::from_secs(5),
+                    self.stream.read_buf(&mut self.buffer),
+                );
+                assert!(read.await.expect("client went quiet").unwrap() > 0);
+            }
+        }
+
+        async fn acknowledge(&mut self, packet: Packet) -> Packet {
+            let mut reply = BytesMut::new();
+            match &packet {
+                Packet::Connect(_) => {
+                    ConnAck::new(ConnectReturnCode::Success, false).write(&mut reply)
+                }
+                Packet::Publish(x) if x.qos == QoS::AtLeastOnce => {
+                    PubAck::new(x.pkid).write(&mut reply)
+                }
+                Packet::Subscribe(x) => {
+                    let granted = vec![SubscribeReasonCode::Success(QoS::AtLeastOnce)];
+                    SubAck::new(x.pkid, granted).write(&mut reply)
+                }
+                Packet::PingReq => rumqttc::PingResp.write(&mut reply),
+                _ => Ok(0),
+            }
+            .unwrap();
+            self.stream.write_all(&reply).await.unwrap();
+            packet
+        }
+
+        async fn publish(&mut self, topic: &str, payload: &str, retain: bool) {
+            let mut publish = Publish::new(topic, QoS::AtMostOnce, payload);
+            publish.retain = retain;
+            let mut buffer = BytesMut::new();
+            publish.write(&mut buffer).unwrap();
+            self.stream.write_all(&buffer).await.unwrap();
+        }
+
+        /// The next publish on `topic`, skipping everything else
+        async fn published(&mut self, topic: &str) -> Publish {
+            loop {
+                if let Packet::Publish(x) = self.recv().await
+                    && x.topic == topic
+                {
+                    return x;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn publishes_status_and_takes_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit = Arc::new(AuditLog::new(dir.path().join("audit.log")));
+        let interlock = Arc::new(Interlock::new(Duration::from_secs(30)));
+        let control = MqttControl {
+            pairing: Arc::new(Pairing::default()),
+            interlock: interlock.clone(),
+            audit: audit.clone(),
+            stop_token: Arc::default(),
+        };
+        let (status_sender, status_watch) = create_status_watch();
+        let (_event_sender, events) = create_event_channel();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(run("127.0.0.1", port, status_watch, events, Some(control)));
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut broker = Broker {
+            stream,
+            buffer: BytesMut::new(),
+        };
+
+        // discovery configs and availability are retained, then the command topics subscribed
+        assert!(matches!(broker.recv().await, Packet::Connect(_)));
+        let button = broker.published("homeassistant/button/snapmaker/stop/config").await;
+        assert!(button.retain);
+        let config: Value = serde_json::from_slice(&button.payload).unwrap();
+        assert_eq!(config["command_topic"], "sm-proxy/snapmaker/command");
+        assert_eq!(config["payload_press"], "stop");
+        let availability = broker.published("sm-proxy/snapmaker/availability").await;
+        assert_eq!(&availability.payload[..], b"online");
+        let mut subscribed = Vec::new();
+        while subscribed.len() < 3 {
+            if let Packet::Subscribe(x) = broker.recv().await {
+                subscribed.extend(x.filters.into_iter().map(|x| x.path));
+            }
+        }
+        assert!(subscribed.contains(&"sm-proxy/snapmaker/command".to_string()));
+
+        status_sender.send_replace(PrinterStatus {
+            status: "RUNNING".to_string(),
+            nozzle_temperature: 205.5,
+            ..Default::default()
+        });
+        let nozzle = broker.published("sm-proxy/snapmaker/status/nozzle_temperature").await;
+        assert!(nozzle.retain);
+        assert_eq!(&nozzle.payload[..], b"205.5");
+
+        // a retained stop is ignored, a live one only arms, the second goes through
+        let command = "sm-proxy/snapmaker/command";
+        // someone armed over HTTP just before
+        let http_token = interlock.arm();
+        broker.publish(command, "stop", true).await;
+        broker.publish(command, "stop", false).await;
+        let entries = recorded(&audit, 1).await;
+        assert_eq!(entries[0].action, "stop_print");
+        assert!(entries[0].response.starts_with("Stopping a print needs"));
+        // arming over MQTT left the HTTP token alone
+        let http_stop = Confirmation {
+            confirm: false,
+            token: Some(http_token),
+        };
+        let status = status_sender.borrow().clone();
+        assert_eq!(interlock.check(Command::Stop, &status, &http_stop), Ok(()));
+        broker.publish(command, "stop", false).await;
+        let entries = recorded(&audit, 2).await;
+        // the interlock let it through, the test has no printer to pair with
+        assert_eq!(entries[0].response, "Not paired with the printer yet");
+    }
+
+    /// Waits until the audit log has `count` entries, most recent first
+    async fn recorded(audit: &AuditLog, count: usize) -> Vec<crate::audit::AuditEntry> {
+        for _ in 0..50 {
+            let entries = audit.recent(10).unwrap();
+            if entries.len() >= count {
+                assert_eq!(entries.len(), count);
+                return entries;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("Expected {count} audit entries");
+    }
+}
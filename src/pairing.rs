@@ -0,0 +1,227 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::Serialize;
+use tokio::sync::{Notify, watch};
+
+use crate::{
+    config::{
+        DISCOVER_PRINTER, DISCOVERY_TIMEOUT, PAIRING_APPROVAL_TIMEOUT, PAIRING_RETRY_INTERVAL,
+    },
+    discovery::discovery,
+    shutdown::{Phase, shutdown},
+    snapmaker_client::{self, Approval},
+    systemd, token_store,
+};
+
+/// How often the printer is asked whether the touchscreen prompt was answered
+const APPROVAL_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum PairingState {
+    /// Trying the token saved by an earlier pairing
+    Refreshing,
+    /// The printer shows a prompt, waiting for someone to tap "Yes"
+    WaitingForApproval {
+        attempt: u32,
+        since: DateTime<Utc>,
+        expires: DateTime<Utc>,
+    },
+    /// The last attempt failed, the next one starts at `retry_at`
+    Retrying {
+        attempt: u32,
+        error: String,
+        retry_at: DateTime<Utc>,
+    },
+    /// An observer without an approved token, it looks for one again every
+    /// retry interval and meanwhile shows what the printer announces
+    WaitingForToken { since: DateTime<Utc> },
+    Paired { since: DateTime<Utc> },
+}
+
+/// The printer token and how pairing is going, shared by everything that
+/// talks to the printer
+pub struct Pairing {
+    token: watch::Sender<Option<String>>,
+    state: watch::Sender<PairingState>,
+    retry: Notify,
+}
+
+impl Default for Pairing {
+    fn default() -> Self {
+        Self {
+            token: watch::Sender::new(None),
+            state: watch::Sender::new(PairingState::Refreshing),
+            retry: Notify::new(),
+        }
+    }
+}
+
+impl Pairing {
+    /// `None` until paired
+    pub fn token(&self) -> Option<String> {
+        self.token.borrow().clone()
+    }
+
+    pub fn state(&self) -> PairingState {
+        self.state.borrow().clone()
+    }
+
+    /// Resolves with the token once paired
+    pub async fn paired(&self) -> String {
+        let mut receiver = self.token.subscribe();
+        let token = receiver.wait_for(Option::is_some).await;
+        token.ok().and_then(|x| x.clone()).unwrap_or_default()
+    }
+
+    /// Follows the state, for someone waiting on a single pairing
+    pub fn subscribe(&self) -> watch::Receiver<PairingState> {
+        self.state.subscribe()
+    }
+
+    /// Skips the wait before the next attempt
+    pub fn retry(&self) {
+        self.retry.notify_one();
+    }
+
+    fn set_state(&self, state: PairingState) {
+        let _ = self.state.send_replace(state);
+    }
+
+    fn set_paired(&self, token: String) {
+        self.set_state(PairingState::Paired { since: Utc::now() });
+        let _ = self.token.send_replace(Some(token));
+    }
+
+    /// The printer stopped accepting the token, the pairing loop starts over
+    pub fn revoke(&self) {
+        // nobody polls until paired again, the watchdog mustn't wait for it
+        systemd::clear_heartbeat();
+        self.set_state(PairingState::Refreshing);
+        let _ = self.token.send_replace(None);
+    }
+
+    /// Resolves once the token was revoked, `false` on shutdown
+    async fn revoked(&self) -> bool {
+        let mut receiver = self.token.subscribe();
+        tokio::select! {
+            _ = receiver.wait_for(Option::is_none) => true,
+            _ = shutdown().reached(Phase::Stopping) => false,
+        }
+    }
+
+    /// Waits for `duration`, a retry request or shutdown, `false` on shutdown
+    async fn pause(&self, duration: Duration) -> bool {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => true,
+            _ = self.retry.notified() => true,
+            _ = shutdown().reached(Phase::Stopping) => false,
+        }
+    }
+}
+
+/// Pairs with the printer without blocking startup. A stored token is
+/// refreshed, otherwise a new one is requested and confirmed on the
+/// touchscreen. An observer only ever uses a stored token. Pairs again
+/// whenever the token gets revoked.
+pub(crate) async fn pairing_loop(pairing: Arc<Pairing>, read_only: bool) {
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let error = match pair(&pairing, attempt, read_only).await {
+            Ok(Some(token)) => {
+                info!("Paired with the printer");
+                systemd::notify("STATUS=Paired with the printer");
+                pairing.set_paired(token);
+                if !pairing.revoked().await {
+                    return;
+                }
+                systemd::notify("STATUS=Not paired: the printer rejected the token");
+                attempt = 0;
+                continue;
+            }
+            // shutting down, or an observer that looks again later
+            Ok(None) => {
+                if !pairing.pause(PAIRING_RETRY_INTERVAL).await {
+                    return;
+                }
+                continue;
+            }
+            Err(e) => e,
+        };
+        warn!("Pairing attempt {attempt} failed: {error}");
+        systemd::notify(&format!("STATUS=Not paired: {error}"));
+        pairing.set_state(PairingState::Retrying {
+            attempt,
+            error,
+            retry_at: Utc::now()
+                + chrono::Duration::from_std(PAIRING_RETRY_INTERVAL).unwrap_or_default(),
+        });
+        if !pairing.pause(PAIRING_RETRY_INTERVAL).await {
+            return;
+        }
+    }
+}
+
+/// One attempt, `Ok(None)` when shutting down or an observer has no token yet
+async fn pair(pairing: &Pairing, attempt: u32, read_only: bool) -> Result<Option<String>, String> {
+    // the first discovery is usually still collecting replies
+    if let Some(name) = DISCOVER_PRINTER
+        && tokio::time::timeout(2 * DISCOVERY_TIMEOUT, discovery().found())
+            .await
+            .is_err()
+    {
+        return Err(format!("Printer {name} not found on the network"));
+    }
+    let waiting_for_token = matches!(pairing.state(), PairingState::WaitingForToken { .. });
+    if !waiting_for_token {
+        pairing.set_state(PairingState::Refreshing);
+    }
+    match snapmaker_client::refresh_stored_token().await {
+        Ok(Some(token)) => return Ok(Some(token)),
+        Ok(None) => (),
+        // don't pair again over a token we can't read, it may just need its key
+        Err(e) => return Err(format!("Failed to use the stored token: {e}")),
+    }
+    if read_only {
+        if !waiting_for_token {
+            info!("No approved token stored yet, showing what the printer announces");
+            systemd::notify("STATUS=Observing without a token");
+            pairing.set_state(PairingState::WaitingForToken { since: Utc::now() });
+        }
+        return Ok(None);
+    }
+
+    let token = snapmaker_client::request_token()
+        .await
+        .map_err(|e| format!("Printer not reachable: {e}"))?;
+    let since = Utc::now();
+    let expires = since + chrono::Duration::from_std(PAIRING_APPROVAL_TIMEOUT).unwrap_or_default();
+    info!("Waiting for the connection to be confirmed on the Snapmaker touchscreen");
+    systemd::notify("STATUS=Waiting for approval on the Snapmaker touchscreen");
+    pairing.set_state(PairingState::WaitingForApproval {
+        attempt,
+        since,
+        expires,
+    });
+    while Utc::now() < expires {
+        match snapmaker_client::check_approval(&token).await {
+            Ok(Approval::Approved) => {
+                token_store::save(&token).map_err(|e| format!("{e:#}"))?;
+                return Ok(Some(token));
+            }
+            Ok(Approval::Rejected) => {
+                return Err("The connection was declined on the touchscreen".to_string());
+            }
+            Ok(Approval::Waiting) => (),
+            // the printer is busy showing the prompt, keep asking
+            Err(e) => warn!("Pairing check failed: {e:#}"),
+        }
+        if !pairing.pause(APPROVAL_POLL_INTERVAL).await {
+            return Ok(None);
+        }
+    }
+    Err("Nobody confirmed the connection on the touchscreen in time".to_string())
+}
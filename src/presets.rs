@@ -0,0 +1,94 @@
+use std::{
+    borrow::Cow,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Context;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+/// Named material temperatures for preheating
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Preset {
+    pub name: Cow<'static, str>,
+    pub nozzle: f64,
+    pub bed: f64,
+    /// Enclosure fan level in percent, left alone when unset
+    #[serde(default)]
+    pub enclosure_fan: Option<u8>,
+}
+
+/// Presets from the config, replaced by the JSON file once edited through the API
+pub struct Presets {
+    path: PathBuf,
+    presets: Mutex<Vec<Preset>>,
+}
+
+impl Presets {
+    pub fn load(path: &str, defaults: &[Preset]) -> Self {
+        let presets = match fs::read_to_string(path) {
+            Ok(content) => match serde_json::from_str(&content) {
+                Ok(x) => {
+                    info!("Loaded presets from {path}");
+                    x
+                }
+                Err(e) => {
+                    warn!("Ignoring invalid presets file {path}: {e}");
+                    defaults.to_vec()
+                }
+            },
+            Err(_) => defaults.to_vec(),
+        };
+        Self {
+            path: PathBuf::from(path),
+            presets: Mutex::new(presets),
+        }
+    }
+
+    pub fn list(&self) -> Vec<Preset> {
+        self.presets
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Preset> {
+        self.list().into_iter().find(|x| x.name == name)
+    }
+
+    /// Adds or replaces the preset with the same name
+    pub fn upsert(&self, preset: Preset) -> anyhow::Result<()> {
+        let mut presets = self.presets.lock().unwrap_or_else(|e| e.into_inner());
+        let mut updated = presets.clone();
+        match updated.iter_mut().find(|x| x.name == preset.name) {
+            Some(existing) => *existing = preset,
+            None => updated.push(preset),
+        }
+        save(&self.path, &updated)?;
+        *presets = updated;
+        Ok(())
+    }
+
+    /// `false` when there was no such preset
+    pub fn remove(&self, name: &str) -> anyhow::Result<bool> {
+        let mut presets = self.presets.lock().unwrap_or_else(|e| e.into_inner());
+        let mut updated = presets.clone();
+        updated.retain(|x| x.name != name);
+        if updated.len() == presets.len() {
+            return Ok(false);
+        }
+        save(&self.path, &updated)?;
+        *presets = updated;
+        Ok(true)
+    }
+}
+
+fn save(path: &Path, presets: &[Preset]) -> anyhow::Result<()> {
+    // write next to the file and rename, a crash never leaves half a file behind
+    let temporary = path.with_extension("json.tmp");
+    fs::write(&temporary, serde_json::to_string_pretty(presets)?)
+        .with_context(|| format!("Failed to write {}", temporary.display()))?;
+    fs::rename(&temporary, path).with_context(|| format!("Failed to replace {}", path.display()))
+}
@@ -0,0 +1,456 @@
+use std::{
+    cmp::Ordering as CmpOrdering,
+    collections::{BinaryHeap, HashMap},
+    fmt,
+    future::Future,
+    sync::{
+        Arc, LazyLock, Mutex, MutexGuard,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use futures::{
+    FutureExt,
+    future::{BoxFuture, Shared},
+};
+use log::debug;
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use crate::{
+    config::{COMMAND_BURST, COMMAND_RATE_PER_MINUTE, MAX_PRINTER_REQUESTS},
+    metrics::metrics,
+    status::{EnclosureStatus, PrinterStatus},
+};
+
+/// Higher priorities are let through to the printer first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Bulk,
+    Poll,
+    /// A stop or pause doesn't wait behind status reads
+    Command,
+}
+
+static SCHEDULER: LazyLock<Scheduler> = LazyLock::new(|| Scheduler::new(MAX_PRINTER_REQUESTS));
+
+pub fn scheduler() -> &'static Scheduler {
+    &SCHEDULER
+}
+
+/// What the scheduler records about a finished printer request
+pub trait Outcome {
+    /// `None` when the printer answered with success
+    fn error(&self) -> Option<String>;
+}
+
+impl Outcome for reqwest::Result<reqwest::Response> {
+    fn error(&self) -> Option<String> {
+        match self {
+            Ok(response) if response.status().is_success() => None,
+            Ok(response) => Some(format!("HTTP {}", response.status())),
+            Err(e) => Some(e.to_string()),
+        }
+    }
+}
+
+impl<T> Outcome for anyhow::Result<T> {
+    fn error(&self) -> Option<String> {
+        self.as_ref().err().map(|e| format!("{e:#}"))
+    }
+}
+
+/// Runs a printer request once the scheduler lets it through
+pub async fn run<T: Outcome>(
+    priority: Priority,
+    kind: &'static str,
+    request: impl Future<Output = T>,
+) -> T {
+    let queued_at = Instant::now();
+    let _permit = scheduler().acquire(priority).await;
+    let waited = queued_at.elapsed();
+    if waited > Duration::from_millis(500) {
+        debug!("{kind} waited {waited:?} for the printer");
+    }
+    if priority != Priority::Poll {
+        metrics().command(kind);
+    }
+    let started_at = Instant::now();
+    let result = request.await;
+    metrics().printer_request(kind, started_at.elapsed(), result.error());
+    result
+}
+
+/// Single gate for everything that talks to the Snapmaker
+pub struct Scheduler {
+    max_concurrent: usize,
+    state: Mutex<QueueState>,
+    clients: Mutex<HashMap<String, TokenBucket>>,
+    pub(crate) status_reads: Coalescer<PrinterStatus>,
+    pub(crate) enclosure_reads: Coalescer<EnclosureStatus>,
+    completed: AtomicU64,
+    rate_limited: AtomicU64,
+    peak_queue_depth: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SchedulerStats {
+    pub max_concurrent: usize,
+    pub in_flight: usize,
+    pub queue_depth: usize,
+    pub peak_queue_depth: u64,
+    pub completed: u64,
+    pub coalesced_reads: u64,
+    pub rate_limited: u64,
+}
+
+struct QueueState {
+    available: usize,
+    next_seq: u64,
+    waiters: BinaryHeap<Waiter>,
+}
+
+struct Waiter {
+    priority: Priority,
+    seq: u64,
+    grant: oneshot::Sender<()>,
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        // highest priority first, then first come first served
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+pub struct Permit<'a> {
+    scheduler: &'a Scheduler,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.scheduler.completed.fetch_add(1, Ordering::Relaxed);
+        self.scheduler.release();
+    }
+}
+
+/// Hands a permit that was granted to an abandoned request back to the queue
+struct PendingPermit<'a> {
+    scheduler: &'a Scheduler,
+    grant: Option<oneshot::Receiver<()>>,
+}
+
+impl Drop for PendingPermit<'_> {
+    fn drop(&mut self) {
+        if let Some(mut grant) = self.grant.take() {
+            grant.close();
+            if grant.try_recv().is_ok() {
+                self.scheduler.release();
+            }
+        }
+    }
+}
+
+impl Scheduler {
+    fn new(max_concurrent: usize) -> Self {
+        Self {
+            max_concurrent,
+            state: Mutex::new(QueueState {
+                available: max_concurrent,
+                next_seq: 0,
+                waiters: BinaryHeap::new(),
+            }),
+            clients: Mutex::new(HashMap::new()),
+            status_reads: Coalescer::default(),
+            enclosure_reads: Coalescer::default(),
+            completed: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            peak_queue_depth: AtomicU64::new(0),
+        }
+    }
+
+    fn queue(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub async fn acquire(&self, priority: Priority) -> Permit<'_> {
+        let grant = {
+            let mut state = self.queue();
+            if state.available > 0 && state.waiters.is_empty() {
+                state.available -= 1;
+                return Permit { scheduler: self };
+            }
+            let (sender, receiver) = oneshot::channel();
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.waiters.push(Waiter {
+                priority,
+                seq,
+                grant: sender,
+            });
+            self.peak_queue_depth
+                .fetch_max(state.waiters.len() as u64, Ordering::Relaxed);
+            receiver
+        };
+
+        let mut pending = PendingPermit {
+            scheduler: self,
+            grant: Some(grant),
+        };
+        if let Some(grant) = pending.grant.as_mut() {
+            // the sender lives in the queue until it is granted
+            let _ = grant.await;
+        }
+        pending.grant = None;
+        Permit { scheduler: self }
+    }
+
+    fn release(&self) {
+        let mut state = self.queue();
+        while let Some(waiter) = state.waiters.pop() {
+            if waiter.grant.send(()).is_ok() {
+                return;
+            }
+        }
+        state.available += 1;
+    }
+
+    /// Token bucket per client for state-changing commands
+    pub fn allow_command(&self, client: &str) -> bool {
+        self.allow_command_at(client, Instant::now())
+    }
+
+    fn allow_command_at(&self, client: &str, now: Instant) -> bool {
+        let capacity = COMMAND_BURST as f64;
+        let refill_per_second = COMMAND_RATE_PER_MINUTE as f64 / 60.0;
+
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        if clients.len() > 1024 {
+            clients
+                .retain(|_, bucket| now.duration_since(bucket.updated) < Duration::from_secs(600));
+        }
+        let bucket = clients.entry(client.to_string()).or_insert(TokenBucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * refill_per_second;
+        bucket.tokens = (bucket.tokens + refill).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            self.rate_limited.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+
+    pub fn stats(&self) -> SchedulerStats {
+        let (available, queue_depth) = {
+            let state = self.queue();
+            (state.available, state.waiters.len())
+        };
+        SchedulerStats {
+            max_concurrent: self.max_concurrent,
+            in_flight: self.max_concurrent - available,
+            queue_depth,
+            peak_queue_depth: self.peak_queue_depth.load(Ordering::Relaxed),
+            completed: self.completed.load(Ordering::Relaxed),
+            coalesced_reads: self.status_reads.coalesced() + self.enclosure_reads.coalesced(),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+type SharedRead<T> = Shared<BoxFuture<'static, Result<T, SharedError>>>;
+
+/// The error of a read, handed to everyone who shared it
+#[derive(Debug, Clone)]
+pub struct SharedError(Arc<anyhow::Error>);
+
+impl SharedError {
+    /// The error the read itself returned, e.g. for a downcast
+    pub fn inner(&self) -> &anyhow::Error {
+        &self.0
+    }
+}
+
+impl fmt::Display for SharedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl std::error::Error for SharedError {}
+
+/// Lets identical reads that overlap share a single printer request
+pub struct Coalescer<T: Clone> {
+    in_flight: Mutex<Option<(String, SharedRead<T>)>>,
+    coalesced: AtomicU64,
+}
+
+impl<T: Clone> Default for Coalescer<T> {
+    fn default() -> Self {
+        Self {
+            in_flight: Mutex::new(None),
+            coalesced: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> Coalescer<T> {
+    pub async fn run<F>(&self, key: &str, request: impl FnOnce() -> F) -> anyhow::Result<T>
+    where
+        F: Future<Output = anyhow::Result<T>> + Send + 'static,
+    {
+        let read = {
+            let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+            match in_flight.as_ref() {
+                Some((in_flight_key, read)) if in_flight_key == key && read.peek().is_none() => {
+                    self.coalesced.fetch_add(1, Ordering::Relaxed);
+                    read.clone()
+                }
+                _ => {
+                    let read = request()
+                        .map(|x| x.map_err(|e| SharedError(Arc::new(e))))
+                        .boxed()
+                        .shared();
+                    *in_flight = Some((key.to_string(), read.clone()));
+                    read
+                }
+            }
+        };
+        read.await.map_err(anyhow::Error::from)
+    }
+
+    fn coalesced(&self) -> u64 {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use tokio::sync::Notify;
+
+    use super::*;
+
+    #[test]
+    fn commands_go_ahead_of_polls_and_polls_ahead_of_bulk() {
+        let scheduler = Scheduler::new(1);
+        let held = scheduler.acquire(Priority::Poll).now_or_never().unwrap();
+        let mut bulk = Box::pin(scheduler.acquire(Priority::Bulk));
+        let mut poll = Box::pin(scheduler.acquire(Priority::Poll));
+        let mut command = Box::pin(scheduler.acquire(Priority::Command));
+        assert!((&mut bulk).now_or_never().is_none());
+        assert!((&mut poll).now_or_never().is_none());
+        assert!((&mut command).now_or_never().is_none());
+        assert_eq!(scheduler.stats().queue_depth, 3);
+
+        drop(held);
+        let held = (&mut command).now_or_never().unwrap();
+        assert!((&mut bulk).now_or_never().is_none());
+        assert!((&mut poll).now_or_never().is_none());
+        drop(held);
+        let held = (&mut poll).now_or_never().unwrap();
+        assert!((&mut bulk).now_or_never().is_none());
+        drop(held);
+        drop((&mut bulk).now_or_never().unwrap());
+        assert_eq!(scheduler.stats().in_flight, 0);
+    }
+
+    #[test]
+    fn abandoned_waiters_free_their_slot() {
+        let scheduler = Scheduler::new(1);
+
+        // given up while still queued
+        let held = scheduler.acquire(Priority::Poll).now_or_never().unwrap();
+        let mut waiter = Box::pin(scheduler.acquire(Priority::Command));
+        assert!((&mut waiter).now_or_never().is_none());
+        drop(waiter);
+        drop(held);
+        assert_eq!(scheduler.stats().in_flight, 0);
+
+        // given up after the slot was handed over, before it was picked up
+        let held = scheduler.acquire(Priority::Poll).now_or_never().unwrap();
+        let mut waiter = Box::pin(scheduler.acquire(Priority::Command));
+        assert!((&mut waiter).now_or_never().is_none());
+        drop(held);
+        assert_eq!(scheduler.stats().in_flight, 1);
+        drop(waiter);
+        assert_eq!(scheduler.stats().in_flight, 0);
+        assert!(scheduler.acquire(Priority::Bulk).now_or_never().is_some());
+    }
+
+    #[tokio::test]
+    async fn overlapping_reads_share_one_request() {
+        let coalescer = Coalescer::<u32>::default();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let answer = Arc::new(Notify::new());
+        let read = || {
+            let requests = requests.clone();
+            let answer = answer.clone();
+            move || async move {
+                requests.fetch_add(1, Ordering::Relaxed);
+                answer.notified().await;
+                anyhow::Ok(42)
+            }
+        };
+
+        let first = coalescer.run("status", read());
+        let second = coalescer.run("status", read());
+        let answered = async {
+            tokio::task::yield_now().await;
+            answer.notify_waiters();
+        };
+        let (first, second, ()) = tokio::join!(first, second, answered);
+        assert_eq!((first.unwrap(), second.unwrap()), (42, 42));
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+        assert_eq!(coalescer.coalesced(), 1);
+
+        // a read after the shared one finished asks the printer again
+        answer.notify_one();
+        assert_eq!(coalescer.run("status", read()).await.unwrap(), 42);
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn commands_over_the_burst_are_refused_until_refilled() {
+        let scheduler = Scheduler::new(1);
+        let start = Instant::now();
+        for _ in 0..COMMAND_BURST {
+            assert!(scheduler.allow_command_at("10.0.0.2", start));
+        }
+        assert!(!scheduler.allow_command_at("10.0.0.2", start));
+        // every client has its own bucket
+        assert!(scheduler.allow_command_at("10.0.0.3", start));
+        assert_eq!(scheduler.stats().rate_limited, 1);
+
+        let refill = Duration::from_secs_f64(60.0 / COMMAND_RATE_PER_MINUTE as f64);
+        assert!(scheduler.allow_command_at("10.0.0.2", start + refill));
+        assert!(!scheduler.allow_command_at("10.0.0.2", start + refill));
+    }
+}
@@ -0,0 +1,133 @@
+use std::{sync::LazyLock, time::Duration};
+
+use actix_web::dev::ServerHandle;
+use log::{info, warn};
+#[cfg(unix)]
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::watch;
+
+use crate::systemd;
+
+static SHUTDOWN: LazyLock<Shutdown> = LazyLock::new(Shutdown::new);
+
+pub fn shutdown() -> &'static Shutdown {
+    &SHUTDOWN
+}
+
+/// Steps of a shutdown, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Running,
+    /// No new uploads, the ones in flight may finish
+    Draining,
+    /// Uploads still in flight give up
+    Aborting,
+    /// Background tasks end
+    Stopping,
+}
+
+pub struct Shutdown {
+    phase: watch::Sender<Phase>,
+    uploads: watch::Sender<usize>,
+}
+
+/// Held by an upload while it talks to the printer
+pub struct UploadGuard(());
+
+impl Drop for UploadGuard {
+    fn drop(&mut self) {
+        shutdown().uploads.send_modify(|x| *x -= 1);
+    }
+}
+
+impl Shutdown {
+    fn new() -> Self {
+        Self {
+            phase: watch::Sender::new(Phase::Running),
+            uploads: watch::Sender::new(0),
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        *self.phase.borrow()
+    }
+
+    /// `None` once the proxy is shutting down
+    pub fn start_upload(&self) -> Option<UploadGuard> {
+        // count first, so draining never misses an upload that got through
+        self.uploads.send_modify(|x| *x += 1);
+        let guard = UploadGuard(());
+        (self.phase() == Phase::Running).then_some(guard)
+    }
+
+    /// Resolves once the shutdown got to `phase`
+    pub async fn reached(&self, phase: Phase) {
+        let mut receiver = self.phase.subscribe();
+        let _ = receiver.wait_for(|x| *x >= phase).await;
+    }
+
+    fn advance(&self, phase: Phase) {
+        self.phase.send_if_modified(|x| {
+            let advanced = phase > *x;
+            if advanced {
+                *x = phase;
+            }
+            advanced
+        });
+    }
+
+    async fn uploads_finished(&self) {
+        let mut receiver = self.uploads.subscribe();
+        let _ = receiver.wait_for(|x| *x == 0).await;
+    }
+}
+
+/// Waits for SIGTERM or SIGINT, lets uploads finish for `drain_timeout`
+/// (a second signal cuts that short), then stops the servers and background tasks
+pub(crate) async fn shutdown_on_signal(servers: Vec<ServerHandle>, drain_timeout: Duration) {
+    let signal = termination_signal().await;
+    info!("Received {signal}, shutting down");
+    systemd::notify("STOPPING=1");
+    shutdown().advance(Phase::Draining);
+
+    let uploads = *shutdown().uploads.borrow();
+    if uploads > 0 {
+        info!("Waiting up to {}s for {uploads} upload(s) to finish", drain_timeout.as_secs());
+    }
+    tokio::select! {
+        _ = shutdown().uploads_finished() => (),
+        _ = tokio::time::sleep(drain_timeout) => warn!("Uploads didn't finish in time, aborting them"),
+        signal = termination_signal() => warn!("Received {signal} again, aborting uploads"),
+    }
+    shutdown().advance(Phase::Aborting);
+    // aborted uploads still answer their client and write the audit log
+    let _ = tokio::time::timeout(Duration::from_secs(5), shutdown().uploads_finished()).await;
+
+    for server in servers {
+        server.stop(true).await;
+    }
+    shutdown().advance(Phase::Stopping);
+}
+
+#[cfg(unix)]
+async fn termination_signal() -> &'static str {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Can't listen for SIGTERM: {e}");
+            let _ = tokio::signal::ctrl_c().await;
+            return "SIGINT";
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+/// Without unix signals only Ctrl+C stops the proxy
+#[cfg(not(unix))]
+async fn termination_signal() -> &'static str {
+    let _ = tokio::signal::ctrl_c().await;
+    "Ctrl+C"
+}
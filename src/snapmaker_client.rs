@@ -1,75 +1,196 @@
-use log::{error, info};
+use chrono::Utc;
+use log::{debug, error, info, warn};
+use rand::Rng;
+use anyhow::Context;
+use reqwest::StatusCode;
 use reqwest::multipart::{Form, Part};
 use serde::{Deserialize, Serialize};
-use std::{fs, path::Path, time::Duration};
-use tokio::sync::watch::Sender;
+use std::{
+    fmt, fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+use std::sync::{LazyLock, Mutex};
+use tokio::sync::{broadcast, watch::Sender};
 
 use crate::{
-    config::{SNAPMAKER_ENDPOINT, TOKEN_FILE},
-    status::{EnclosureStatus, PrinterStatus},
+    activity::{activity, poll_interval},
+    config::{
+        ENCLOSURE_POLL_INTERVAL, KEEP_ALIVE_HANG_TIMEOUT, POLL_INTERVAL, PRINTER_CONNECT_TIMEOUT,
+        PRINTER_REQUEST_TIMEOUT, PRINTER_UPLOAD_TIMEOUT, RECONNECT_MAX_BACKOFF,
+    },
+    discovery::{discovery, endpoint},
+    events::{self, DISCONNECT_AFTER_FAILURES, EventDetector, PrinterEvent},
+    history::{TemperatureHistory, TemperatureSample},
+    metrics::metrics,
+    scheduler::{self, Priority, SharedError, scheduler},
+    shutdown::{Phase, shutdown},
+    systemd,
+    status::{ConnectionState, EnclosureStatus, PrinterStatus},
+    token_store,
 };
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SnapmakerTokenResponse {
     pub token: String,
+    /// Machine series, e.g. "Snapmaker 2.0 A350"
+    #[serde(default)]
+    pub series: Option<String>,
+    #[serde(default, rename = "headType")]
+    pub head_type: Option<String>,
+}
+
+/// Every printer request gives up after `PRINTER_REQUEST_TIMEOUT`, a hung one would
+/// otherwise hold up its caller for good
+fn client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(PRINTER_CONNECT_TIMEOUT)
+        .timeout(PRINTER_REQUEST_TIMEOUT)
+        .build()
+        .expect("Failed to create the HTTP client")
+}
+
+/// The printer no longer accepts the token, e.g. after a factory reset, it has
+/// to be paired again
+#[derive(Debug)]
+pub struct Unauthorized(StatusCode);
+
+impl fmt::Display for Unauthorized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The printer rejected the token: {}", self.0)
+    }
+}
+
+impl std::error::Error for Unauthorized {}
+
+impl Unauthorized {
+    /// `401` and `403` answer a token the printer doesn't know (anymore)
+    fn check(status: StatusCode) -> Result<(), Self> {
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(Self(status)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Also behind a read that was shared with other callers
+    pub fn is(error: &anyhow::Error) -> bool {
+        let error = error.downcast_ref::<SharedError>().map_or(error, SharedError::inner);
+        error.is::<Self>()
+    }
+}
+
+/// What the printer told us about itself, for diagnostics
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PrinterInfo {
+    pub model: Option<String>,
+    pub head_type: Option<String>,
+    pub firmware: Option<String>,
+}
+
+static PRINTER_INFO: LazyLock<Mutex<PrinterInfo>> = LazyLock::new(Default::default);
+
+pub fn printer_info() -> PrinterInfo {
+    PRINTER_INFO
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+fn remember_connection(response: &SnapmakerTokenResponse) {
+    let mut info = PRINTER_INFO.lock().unwrap_or_else(|e| e.into_inner());
+    info.model = response.series.clone();
+    info.head_type = response.head_type.clone();
 }
 
-pub async fn get_snapmaker_token() -> Result<String, Box<dyn std::error::Error>> {
-    let auth_url = format!("{}/api/v1/connect", SNAPMAKER_ENDPOINT);
-    let client = reqwest::Client::new();
+/// Asks the firmware for its version with M115, once, the answer is kept
+pub async fn firmware_version(token: &str) -> anyhow::Result<String> {
+    if let Some(firmware) = printer_info().firmware {
+        return Ok(firmware);
+    }
+    let response = execute_gcode(token, "M115").await?;
+    // the first line names the firmware (FIRMWARE_NAME:...), capability lines follow
+    let firmware = response
+        .lines()
+        .map(str::trim)
+        .find(|x| !x.is_empty() && !x.starts_with("Cap:") && *x != "ok")
+        .map(|x| x.strip_prefix("FIRMWARE_NAME:").unwrap_or(x).to_string())
+        .ok_or_else(|| anyhow::anyhow!("M115 returned no firmware version"))?;
+    PRINTER_INFO
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .firmware = Some(firmware.clone());
+    Ok(firmware)
+}
+
+/// Exchanges the token saved by an earlier pairing for a fresh one, without
+/// prompting on the touchscreen
+pub async fn refresh_stored_token() -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let auth_url = format!("{}/api/v1/connect", endpoint());
+    let client = client();
 
     // Try to read existing token
-    if let Ok(token) = fs::read_to_string(TOKEN_FILE) {
+    if let Some(token) = token_store::load()? {
         let form_data = [("token", token)];
-        match client.post(&auth_url).form(&form_data).send().await {
+        let request = client.post(&auth_url).form(&form_data).send();
+        match scheduler::run(Priority::Command, "connect", request).await {
             Ok(response) => {
                 let status = response.status();
-                if status.is_success() {
-                    if let Ok(body) = response.text().await {
-                        if let Ok(json_response) =
-                            serde_json::from_str::<SnapmakerTokenResponse>(&body)
-                        {
-                            let new_token = json_response.token;
-                            // Save the new token
-                            info!("Obtained refresh token");
-                            fs::write(TOKEN_FILE, &new_token)?;
-                            return Ok(new_token);
-                        }
-                    }
+                if status.is_success()
+                    && let Ok(body) = response.text().await
+                    && let Ok(json_response) = serde_json::from_str::<SnapmakerTokenResponse>(&body)
+                {
+                    remember_connection(&json_response);
+                    let new_token = json_response.token;
+                    // Save the new token
+                    info!("Obtained refresh token");
+                    token_store::save(&new_token)?;
+                    return Ok(Some(new_token));
                 }
             }
-            Err(e) => {
-                error!("Error using existing token: {}", e);
-            }
+            // an unreachable printer says nothing about the token, let the caller tell
+            Err(e) => return Err(e.into()),
         }
     }
+    Ok(None)
+}
 
-    // If we get here, we need to request a new token
-    println!("No valid token found. Requesting new token...");
-    println!("Please authorize the connection on your Snapmaker touchscreen");
-
-    match client.post(&auth_url).send().await {
-        Ok(response) => {
-            let status = response.status();
-            if status.is_success() {
-                if let Ok(body) = response.text().await {
-                    if let Ok(json_response) = serde_json::from_str::<SnapmakerTokenResponse>(&body)
-                    {
-                        let token = json_response.token.clone();
-                        // Save the token
-                        println!("Obtained authorization token");
-                        fs::write(TOKEN_FILE, &token)?;
-                        return Ok(token);
-                    }
-                }
-            }
-            error!("Failed to get token: {}", status);
-            Err("Failed to get token".into())
-        }
-        Err(e) => {
-            error!("Error requesting new token: {}", e);
-            Err(e.into())
-        }
+/// Starts a pairing, the token only works once someone taps "Yes" on the touchscreen
+pub async fn request_token() -> anyhow::Result<String> {
+    let auth_url = format!("{}/api/v1/connect", endpoint());
+    let client = client();
+    let request = client.post(&auth_url).send();
+    let response = scheduler::run(Priority::Command, "connect", request).await?;
+    let status = response.status();
+    if !status.is_success() {
+        anyhow::bail!("Connect request failed: {status}");
+    }
+    let body = response.text().await?;
+    let token_response: SnapmakerTokenResponse =
+        serde_json::from_str(&body).context("Connect response without a token")?;
+    remember_connection(&token_response);
+    Ok(token_response.token)
+}
+
+/// Where a pairing stands on the touchscreen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Approval {
+    Approved,
+    Waiting,
+    Rejected,
+}
+
+/// The printer answers status requests with 204 until the connection is
+/// confirmed and with 401 once it was declined or timed out
+pub async fn check_approval(token: &str) -> anyhow::Result<Approval> {
+    let status_url = format!("{}/api/v1/status?token={}", endpoint(), token);
+    let client = client();
+    let request = client.get(&status_url).send();
+    let response = scheduler::run(Priority::Command, "pairing", request).await?;
+    match response.status() {
+        StatusCode::NO_CONTENT => Ok(Approval::Waiting),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Ok(Approval::Rejected),
+        x if x.is_success() => Ok(Approval::Approved),
+        x => anyhow::bail!("Pairing check failed: {x}"),
     }
 }
 
@@ -78,7 +199,7 @@ pub async fn upload_file_to_snapmaker(
     file_path: &Path,
     filename: &str,
 ) -> anyhow::Result<()> {
-    let client = reqwest::Client::new();
+    let client = client();
     let file_content = fs::read(file_path)?;
 
     // below is the UPLOAD API, but this does not work if you want to start
@@ -103,7 +224,8 @@ pub async fn upload_file_to_snapmaker(
     // }
 
     // prepare
-    let upload_url = format!("{}/api/v1/prepare_print", SNAPMAKER_ENDPOINT);
+    let upload_url = format!("{}/api/v1/prepare_print", endpoint());
+    let file_size = file_content.len();
     let file_part = Part::bytes(file_content)
         .file_name(filename.to_string())
         .mime_str("application/octet-stream")?;
@@ -112,20 +234,26 @@ pub async fn upload_file_to_snapmaker(
         .text("token", token.to_string())
         .text("type", "3DP".to_string());
 
-    let response = client.post(&upload_url).multipart(form).send().await?;
+    let request = client
+        .post(&upload_url)
+        .timeout(PRINTER_UPLOAD_TIMEOUT)
+        .multipart(form)
+        .send();
+    let response = scheduler::run(Priority::Bulk, "prepare_print", request).await?;
     if !response.status().is_success() {
         let status = response.status();
         let text = response.text().await.unwrap_or_default();
         anyhow::bail!("Prepare on Snapmaker failed {status:?} {text}",)
     }
+    metrics().upload(file_size);
 
     Ok(())
 }
 
 pub async fn start_print(token: &str) -> anyhow::Result<()> {
-    let url = format!("{}/api/v1/start_print?token={}", SNAPMAKER_ENDPOINT, token);
-    let client = reqwest::Client::new();
-    let response = client.post(&url).send().await?;
+    let url = format!("{}/api/v1/start_print?token={}", endpoint(), token);
+    let client = client();
+    let response = scheduler::run(Priority::Command, "start_print", client.post(&url).send()).await?;
 
     if !response.status().is_success() {
         error!("Snapmaker start failed: {}", response.status());
@@ -136,34 +264,56 @@ pub async fn start_print(token: &str) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Concurrent status reads are merged into one printer request
 pub async fn get_status(token: &str) -> anyhow::Result<PrinterStatus> {
+    let owned_token = token.to_string();
+    scheduler()
+        .status_reads
+        .run(token, move || async move {
+            scheduler::run(Priority::Poll, "status", fetch_status(&owned_token)).await
+        })
+        .await
+}
+
+async fn fetch_status(token: &str) -> anyhow::Result<PrinterStatus> {
     let status_url = format!(
         "{}/api/v1/status?token={}&{}",
-        SNAPMAKER_ENDPOINT,
+        endpoint(),
         token,
         chrono::Utc::now().timestamp()
     );
-    let client = reqwest::Client::new();
+    let client = client();
 
     let response = client.get(&status_url).send().await?;
 
+    Unauthorized::check(response.status())?;
     if !response.status().is_success() {
         anyhow::bail!("Keepalive request failed: {}", response.status());
     }
     let mut status: PrinterStatus = serde_json::from_str(&response.text().await?)?;
     // Snapmaker seems to report speed in mm/h ?!
-    status.work_speed = status.work_speed / 60.0;
+    status.work_speed /= 60.0;
     Ok(status)
 }
 
 pub async fn get_enclosure_status(token: &str) -> anyhow::Result<EnclosureStatus> {
+    let owned_token = token.to_string();
+    scheduler()
+        .enclosure_reads
+        .run(token, move || async move {
+            scheduler::run(Priority::Poll, "enclosure", fetch_enclosure_status(&owned_token)).await
+        })
+        .await
+}
+
+async fn fetch_enclosure_status(token: &str) -> anyhow::Result<EnclosureStatus> {
     let status_url = format!(
         "{}/api/v1/enclosure?token={}&{}",
-        SNAPMAKER_ENDPOINT,
+        endpoint(),
         token,
         chrono::Utc::now().timestamp()
     );
-    let client = reqwest::Client::new();
+    let client = client();
 
     let response = client.get(&status_url).send().await?;
 
@@ -175,14 +325,14 @@ pub async fn get_enclosure_status(token: &str) -> anyhow::Result<EnclosureStatus
 }
 
 pub async fn set_enclosure_light(token: &str, value: u8) -> anyhow::Result<()> {
-    let api_url = format!("{}/api/v1/enclosure", SNAPMAKER_ENDPOINT);
-    let client = reqwest::Client::new();
+    let api_url = format!("{}/api/v1/enclosure", endpoint());
+    let client = client();
 
-    let response = client
+    let request = client
         .post(&api_url)
         .form(&[("token", token), ("led", value.to_string().as_str())])
-        .send()
-        .await?;
+        .send();
+    let response = scheduler::run(Priority::Command, "set_enclosure_light", request).await?;
 
     if !response.status().is_success() {
         anyhow::bail!("Set enclosure light failed: {}", response.status());
@@ -192,14 +342,14 @@ pub async fn set_enclosure_light(token: &str, value: u8) -> anyhow::Result<()> {
 }
 
 pub async fn set_enclosure_fan(token: &str, value: u8) -> anyhow::Result<()> {
-    let api_url = format!("{}/api/v1/enclosure", SNAPMAKER_ENDPOINT);
-    let client = reqwest::Client::new();
+    let api_url = format!("{}/api/v1/enclosure", endpoint());
+    let client = client();
 
-    let response = client
+    let request = client
         .post(&api_url)
         .form(&[("token", token), ("fan", value.to_string().as_str())])
-        .send()
-        .await?;
+        .send();
+    let response = scheduler::run(Priority::Command, "set_enclosure_fan", request).await?;
 
     if !response.status().is_success() {
         anyhow::bail!("Set enclosure fan failed: {}", response.status());
@@ -209,9 +359,9 @@ pub async fn set_enclosure_fan(token: &str, value: u8) -> anyhow::Result<()> {
 }
 
 pub async fn pause_print(token: &str) -> anyhow::Result<()> {
-    let url = format!("{}/api/v1/pause_print?token={}", SNAPMAKER_ENDPOINT, token);
-    let client = reqwest::Client::new();
-    let response = client.post(&url).send().await?;
+    let url = format!("{}/api/v1/pause_print?token={}", endpoint(), token);
+    let client = client();
+    let response = scheduler::run(Priority::Command, "pause_print", client.post(&url).send()).await?;
 
     if !response.status().is_success() {
         let status = response.status();
@@ -222,22 +372,23 @@ pub async fn pause_print(token: &str) -> anyhow::Result<()> {
 }
 
 pub async fn stop_print(token: &str) -> anyhow::Result<()> {
-    let url = format!("{}/api/v1/stop_print?token={}", SNAPMAKER_ENDPOINT, token);
-    let client = reqwest::Client::new();
-    let response = client.post(&url).send().await?;
+    let url = format!("{}/api/v1/stop_print?token={}", endpoint(), token);
+    let client = client();
+    let response = scheduler::run(Priority::Command, "stop_print", client.post(&url).send()).await?;
 
     if !response.status().is_success() {
         let status = response.status();
         let text = response.text().await.unwrap_or_default();
         anyhow::bail!("Stop print failed {status:?} {text}",)
     }
+    events::stop_sent();
     Ok(())
 }
 
 pub async fn resume_print(token: &str) -> anyhow::Result<()> {
-    let url = format!("{}/api/v1/resume_print?token={}", SNAPMAKER_ENDPOINT, token);
-    let client = reqwest::Client::new();
-    let response = client.post(&url).send().await?;
+    let url = format!("{}/api/v1/resume_print?token={}", endpoint(), token);
+    let client = client();
+    let response = scheduler::run(Priority::Command, "resume_print", client.post(&url).send()).await?;
 
     if !response.status().is_success() {
         let status = response.status();
@@ -247,28 +398,142 @@ pub async fn resume_print(token: &str) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Runs G-code on the printer and returns whatever it answered
+pub async fn execute_gcode(token: &str, code: &str) -> anyhow::Result<String> {
+    let api_url = format!("{}/api/v1/execute_code", endpoint());
+    let client = client();
+
+    let request = client
+        .post(&api_url)
+        .form(&[("token", token), ("code", code)])
+        .send();
+    let response = scheduler::run(Priority::Command, "execute_code", request).await?;
+
+    let status = response.status();
+    let text = response.text().await.unwrap_or_default();
+    if !status.is_success() {
+        anyhow::bail!("Execute G-code failed {status:?} {text}")
+    }
+    Ok(text.trim().to_string())
+}
+
+/// Polls with `token` until shutdown, or fails with [`Unauthorized`] once the
+/// printer stops accepting it
 pub(crate) async fn keep_alive_loop(
     token: String,
-    status_sender: Sender<PrinterStatus>,
+    status_sender: &Sender<PrinterStatus>,
+    event_sender: &broadcast::Sender<PrinterEvent>,
+    history: &TemperatureHistory,
 ) -> anyhow::Result<()> {
+    let mut detector = EventDetector::default();
+    let mut failures = 0;
+    let mut last_seen = None;
+    let mut enclosure: Option<(Instant, EnclosureStatus)> = None;
+    let mut asked_firmware = false;
     loop {
-        let enclosure = match get_enclosure_status(&token).await {
-            Ok(x) => x,
-            Err(e) => {
-                error!("Error getting enclosure status {e:?}");
-                EnclosureStatus::default()
-            }
-        };
-
-        match get_status(&token).await {
+        let events = match get_status(&token).await {
             Ok(mut status) => {
-                status.enclosure = enclosure;
+                metrics().keep_alive(true);
+                if failures > 0 {
+                    info!("Printer answered again after {failures} failed polls");
+                }
+                failures = 0;
+                // the enclosure changes slowly, it doesn't need every poll
+                if enclosure
+                    .as_ref()
+                    .is_none_or(|(at, _)| at.elapsed() >= ENCLOSURE_POLL_INTERVAL)
+                {
+                    let fetched = match get_enclosure_status(&token).await {
+                        Ok(x) => x,
+                        Err(e) => {
+                            error!("Error getting enclosure status {e:?}");
+                            EnclosureStatus::default()
+                        }
+                    };
+                    enclosure = Some((Instant::now(), fetched));
+                }
+                // asked once, between jobs, so diagnostics never has to talk to the printer
+                if !asked_firmware && status.status == "IDLE" {
+                    asked_firmware = true;
+                    if let Err(e) = firmware_version(&token).await {
+                        warn!("Failed to read the firmware version: {e:#}");
+                    }
+                }
+                status.enclosure = enclosure.as_ref().map(|(_, x)| x.clone()).unwrap_or_default();
+                last_seen = Some(Utc::now());
+                status.connection = ConnectionState::Connected;
+                status.last_seen = last_seen;
+                let events = detector.observe(&status);
+                status.stalled_since = detector.stalled_since();
+                history.push(TemperatureSample::from_status(&status));
                 let _ = status_sender.send(status);
                 info!("Updated printer status");
+                events
+            }
+            // retrying won't help, the token is gone for good
+            Err(e) if Unauthorized::is(&e) => {
+                metrics().keep_alive(false);
+                error!("{e}, pairing again");
+                let _ = status_sender.send(PrinterStatus::unavailable("Printer not paired"));
+                return Err(e);
+            }
+            Err(e) => {
+                metrics().keep_alive(false);
+                failures += 1;
+                if failures < DISCONNECT_AFTER_FAILURES {
+                    warn!("Keepalive failed, connection degraded: {e}");
+                    // keep the last snapshot for a moment, but say it is old
+                    status_sender.send_modify(|status| {
+                        if status.connection == ConnectionState::Connected {
+                            status.connection = ConnectionState::Degraded;
+                            status.stale = true;
+                        }
+                    });
+                } else {
+                    if failures == DISCONNECT_AFTER_FAILURES {
+                        error!("Printer is offline: {e}");
+                        // it may just have a new address
+                        discovery().rescan();
+                    } else {
+                        debug!("Printer still offline: {e}");
+                    }
+                    let _ = status_sender.send(PrinterStatus::offline(last_seen));
+                }
+                detector.observe_failure(&e.to_string())
+            }
+        };
+        for event in events {
+            info!("{}: {}", event.kind.title(), event.message);
+            // nobody listening is fine
+            let _ = event_sender.send(event);
+        }
+        let wait = if failures > 0 {
+            reconnect_delay(failures)
+        } else {
+            poll_interval(&status_sender.borrow(), activity().observed(Instant::now()))
+        };
+        systemd::expect_heartbeat_within(wait + KEEP_ALIVE_HANG_TIMEOUT);
+        if failures == 0 && wait > POLL_INTERVAL {
+            debug!("Printer idle and unobserved, next poll in {}s", wait.as_secs());
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => (),
+            _ = activity().observer_arrived(), if failures == 0 => {
+                debug!("Observer arrived, polling now")
+            }
+            _ = shutdown().reached(Phase::Stopping) => {
+                info!("Keep-alive stopped");
+                return Ok(());
             }
-            Err(e) => error!("Keepalive failed: {}", e),
         }
-        // Sleep for 3 seconds before next keepalive
-        tokio::time::sleep(Duration::from_secs(1)).await;
     }
 }
+
+/// Exponential backoff with jitter while the printer doesn't answer
+fn reconnect_delay(failures: u32) -> Duration {
+    let backoff = POLL_INTERVAL
+        .saturating_mul(1 << (failures - 1).min(16))
+        .min(RECONNECT_MAX_BACKOFF);
+    // spread retries over the upper half so they don't all land at the same moment
+    backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
@@ -1,3 +1,4 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use tokio::sync::watch;
 
@@ -27,14 +28,39 @@ pub struct PrinterStatus {
     pub remaining_time: f64,
     pub print_status: String,
     #[serde(default)]
-    pub enclosure: EnclosureStatus
+    pub enclosure: EnclosureStatus,
+    /// Set by the proxy while a running print makes no progress
+    #[serde(default)]
+    pub stalled_since: Option<DateTime<Utc>>,
+    /// How the proxy currently reaches the printer, set by the keep-alive loop
+    #[serde(default)]
+    pub connection: ConnectionState,
+    /// Last successful status poll
+    #[serde(default)]
+    pub last_seen: Option<DateTime<Utc>>,
+    /// The values are from the last successful poll, not live
+    #[serde(default)]
+    pub stale: bool,
+    /// Not paired, only the state the printer announces on the network is known
+    #[serde(default)]
+    pub unpaired: bool
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    Connected,
+    /// Polls are failing, the printer may be back any moment
+    Degraded,
+    #[default]
+    Offline,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 #[serde(default, rename_all(deserialize="camelCase"))]
 pub struct EnclosureStatus {
-    led: u8,
-    fan: u8
+    pub led: u8,
+    pub fan: u8
 }
 
 impl Default for PrinterStatus {
@@ -56,8 +82,62 @@ impl Default for PrinterStatus {
             elapsed_time: 0.0,
             remaining_time: 0.0,
             print_status: "Idle".to_string(),
-            enclosure: EnclosureStatus::default()
+            enclosure: EnclosureStatus::default(),
+            stalled_since: None,
+            connection: ConnectionState::default(),
+            last_seen: None,
+            stale: false,
+            unpaired: false
+        }
+    }
+}
+
+impl PrinterStatus {
+    /// Placeholder while no status can be fetched from the printer
+    pub fn unavailable(reason: &str) -> Self {
+        Self {
+            status: "UNKNOWN".to_string(),
+            file_name: String::new(),
+            print_status: reason.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// What is served once the printer is gone, no old temperatures
+    pub fn offline(last_seen: Option<DateTime<Utc>>) -> Self {
+        Self {
+            last_seen,
+            stale: true,
+            ..Self::unavailable("Offline")
+        }
+    }
+
+    /// What an observer without a token learns from the printer's discovery reply
+    pub fn announced(state: &str, model: Option<&str>) -> Self {
+        let mut print_status = state.to_ascii_lowercase();
+        if let Some(first) = print_status.get_mut(..1) {
+            first.make_ascii_uppercase();
+        }
+        if let Some(model) = model {
+            print_status = format!("{print_status}, {model}");
         }
+        Self {
+            status: state.to_string(),
+            file_name: String::new(),
+            print_status,
+            connection: ConnectionState::Connected,
+            unpaired: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_operational(&self) -> bool {
+        self.connection != ConnectionState::Offline && self.status != "UNKNOWN" && !self.unpaired
+    }
+
+    /// Temperatures, position and progress are live readings, not placeholders
+    pub fn has_readings(&self) -> bool {
+        self.status != "UNKNOWN" && !self.stale && !self.unpaired
     }
 }
 
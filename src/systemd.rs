@@ -0,0 +1,299 @@
+#[cfg(target_os = "linux")]
+use std::os::{
+    fd::{FromRawFd, RawFd},
+    linux::net::SocketAddrExt,
+    unix::net::{SocketAddr, UnixDatagram},
+};
+use std::{
+    env,
+    net::TcpListener,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+use tokio::sync::watch;
+
+use crate::status::{ConnectionState, PrinterStatus};
+
+/// First file descriptor systemd passes with socket activation
+#[cfg(target_os = "linux")]
+const LISTEN_FDS_START: RawFd = 3;
+
+static HEARTBEAT: LazyLock<Mutex<Option<Instant>>> = LazyLock::new(Default::default);
+
+/// Sends a state change to systemd, a no-op when not started by systemd with
+/// `Type=notify`
+pub fn notify(state: &str) {
+    if let Ok(path) = env::var("NOTIFY_SOCKET") {
+        notify_at(&path, state);
+    }
+}
+
+fn notify_at(path: &str, state: &str) {
+    if let Err(e) = send(path, state) {
+        warn!("Failed to notify systemd at {path}: {e}");
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send(path: &str, state: &str) -> std::io::Result<()> {
+    let address = match path.strip_prefix('@') {
+        // abstract socket, the leading @ stands for a NUL byte
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+/// systemd only runs on Linux, there is nobody to notify elsewhere
+#[cfg(not(target_os = "linux"))]
+fn send(_path: &str, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// The keep-alive loop promises to come back within `within`, the watchdog is
+/// only fed while that promise holds
+pub fn expect_heartbeat_within(within: Duration) {
+    *HEARTBEAT.lock().unwrap_or_else(|e| e.into_inner()) = Some(Instant::now() + within);
+}
+
+/// Nothing to promise while the keep-alive loop doesn't run, e.g. while pairing
+/// waits for the touchscreen, the watchdog is fed regardless
+pub fn clear_heartbeat() {
+    *HEARTBEAT.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+fn heartbeat_overdue() -> bool {
+    HEARTBEAT
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .is_some_and(|x| Instant::now() > x)
+}
+
+/// `WatchdogSec=` from the unit, when it applies to this process
+fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Ok(pid) = env::var("WATCHDOG_PID")
+        && pid.parse() != Ok(std::process::id())
+    {
+        return None;
+    }
+    Some(Duration::from_micros(usec))
+}
+
+/// Feeds the watchdog while the keep-alive loop is alive and keeps `STATUS=`
+/// in line with the printer
+pub(crate) async fn systemd_loop(status_watch: watch::Receiver<PrinterStatus>) {
+    let Ok(socket) = env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    run(&socket, watchdog_interval(), status_watch).await
+}
+
+async fn run(
+    socket: &str,
+    watchdog: Option<Duration>,
+    mut status_watch: watch::Receiver<PrinterStatus>,
+) {
+    if let Some(interval) = watchdog {
+        info!("Feeding the systemd watchdog every {}s", interval.as_secs_f64() / 2.0);
+    }
+    // ping twice per interval, as systemd recommends
+    let mut ping = watchdog.map(|x| tokio::time::interval(x / 2));
+    let mut last_status = String::new();
+    loop {
+        tokio::select! {
+            _ = async { ping.as_mut().unwrap().tick().await }, if ping.is_some() => {
+                if heartbeat_overdue() {
+                    warn!("Keep-alive loop is overdue, letting the systemd watchdog fire");
+                } else {
+                    notify_at(socket, "WATCHDOG=1");
+                }
+            }
+            changed = status_watch.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                let status = describe(&status_watch.borrow_and_update());
+                if status != last_status {
+                    debug!("systemd status: {status}");
+                    notify_at(socket, &format!("STATUS={status}"));
+                    last_status = status;
+                }
+            }
+        }
+    }
+}
+
+fn describe(status: &PrinterStatus) -> String {
+    match status.connection {
+        // "Offline" or why there is no status at all
+        ConnectionState::Offline => status.print_status.clone(),
+        ConnectionState::Degraded => format!("Printer {} (connection degraded)", status.status),
+        ConnectionState::Connected if status.status == "RUNNING" => format!(
+            "Printer RUNNING {} {:.0}%",
+            status.file_name,
+            status.progress * 100.0
+        ),
+        ConnectionState::Connected => format!("Printer {}", status.status),
+    }
+}
+
+/// Sockets passed by a systemd `.socket` unit, empty without socket activation
+#[cfg(target_os = "linux")]
+pub fn listeners() -> Vec<TcpListener> {
+    let Some(count) = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|x| x.parse::<RawFd>().ok())
+    else {
+        return Vec::new();
+    };
+    if env::var("LISTEN_PID").ok().and_then(|x| x.parse().ok()) != Some(std::process::id()) {
+        return Vec::new();
+    }
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .filter_map(|fd| {
+            // SAFETY: systemd hands these descriptors to this process (LISTEN_PID
+            // matched), nothing else in the process owns them
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            match listener.set_nonblocking(true).and(listener.local_addr()) {
+                Ok(address) => {
+                    info!("Listening on {address} from systemd");
+                    Some(listener)
+                }
+                Err(e) => {
+                    warn!("Ignoring file descriptor {fd} from systemd: {e}");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn listeners() -> Vec<TcpListener> {
+    Vec::new()
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    /// Stands in for systemd's notification socket
+    struct FakeNotifySocket {
+        _dir: tempfile::TempDir,
+        path: String,
+        socket: UnixDatagram,
+    }
+
+    impl FakeNotifySocket {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("notify").to_string_lossy().into_owned();
+            let socket = UnixDatagram::bind(&path).unwrap();
+            socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+            Self {
+                _dir: dir,
+                path,
+                socket,
+            }
+        }
+
+        fn recv(&self) -> String {
+            let mut buffer = [0; 256];
+            let length = self.socket.recv(&mut buffer).unwrap();
+            String::from_utf8_lossy(&buffer[..length]).into_owned()
+        }
+
+        /// Messages that arrive within `within`
+        fn drain(&self, within: Duration) -> Vec<String> {
+            let deadline = std::time::Instant::now() + within;
+            let mut buffer = [0; 256];
+            let mut messages = Vec::new();
+            loop {
+                let left = deadline.saturating_duration_since(std::time::Instant::now());
+                if left.is_zero() {
+                    break;
+                }
+                self.socket.set_read_timeout(Some(left)).unwrap();
+                if let Ok(length) = self.socket.recv(&mut buffer) {
+                    messages.push(String::from_utf8_lossy(&buffer[..length]).into_owned());
+                }
+            }
+            self.socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+            messages
+        }
+    }
+
+    #[test]
+    fn sends_to_path_and_abstract_sockets() {
+        let fake = FakeNotifySocket::new();
+        send(&fake.path, "READY=1").unwrap();
+        assert_eq!(fake.recv(), "READY=1");
+
+        let name = format!("sm-proxy-test-{}", std::process::id());
+        let socket = UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap())
+            .unwrap();
+        send(&format!("@{name}"), "STATUS=Paired").unwrap();
+        let mut buffer = [0; 64];
+        let length = socket.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"STATUS=Paired");
+    }
+
+    #[tokio::test]
+    async fn watchdog_follows_the_keep_alive_loop() {
+        let fake = FakeNotifySocket::new();
+        let (status_sender, status_watch) = watch::channel(PrinterStatus::default());
+        expect_heartbeat_within(Duration::from_secs(60));
+        let path = fake.path.clone();
+        tokio::spawn(async move {
+            run(&path, Some(Duration::from_millis(100)), status_watch).await
+        });
+
+        status_sender.send_replace(PrinterStatus {
+            status: "RUNNING".to_string(),
+            file_name: "part.gcode".to_string(),
+            progress: 0.5,
+            connection: ConnectionState::Connected,
+            ..Default::default()
+        });
+        let messages = tokio::task::spawn_blocking(move || {
+            let messages = fake.drain(Duration::from_millis(300));
+            (fake, messages)
+        });
+        let (fake, messages) = messages.await.unwrap();
+        assert!(messages.contains(&"STATUS=Printer RUNNING part.gcode 50%".to_string()));
+        assert!(messages.iter().filter(|x| *x == "WATCHDOG=1").count() >= 2);
+
+        // a hung loop stops the pings, systemd restarts us
+        expect_heartbeat_within(Duration::ZERO);
+        let messages = tokio::task::spawn_blocking(move || {
+            // a ping may already be on its way
+            fake.drain(Duration::from_millis(60));
+            let messages = fake.drain(Duration::from_millis(300));
+            (fake, messages)
+        });
+        let (fake, messages) = messages.await.unwrap();
+        assert_eq!(messages, Vec::<String>::new());
+
+        // the keep-alive loop stopped for pairing, nothing to be overdue on
+        clear_heartbeat();
+        let messages = tokio::task::spawn_blocking(move || fake.drain(Duration::from_millis(300)));
+        let messages = messages.await.unwrap();
+        assert!(messages.iter().filter(|x| *x == "WATCHDOG=1").count() >= 2);
+    }
+
+    #[test]
+    fn describes_the_printer() {
+        let offline = PrinterStatus::offline(None);
+        assert_eq!(describe(&offline), "Offline");
+        let degraded = PrinterStatus {
+            connection: ConnectionState::Degraded,
+            ..Default::default()
+        };
+        assert_eq!(describe(&degraded), "Printer IDLE (connection degraded)");
+    }
+}
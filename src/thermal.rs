@@ -0,0 +1,544 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::{error, warn};
+use serde_json::json;
+use tokio::sync::{broadcast, watch};
+
+use crate::{
+    audit::AuditLog,
+    config::THERMAL_WATCHDOG,
+    events::{EventKind, PrinterEvent},
+    pairing::Pairing,
+    snapmaker_client,
+    status::PrinterStatus,
+};
+
+/// How far the watchdog may go for an anomaly, it starts at `Notify` and
+/// escalates while the anomaly persists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ThermalAction {
+    Notify,
+    Pause,
+    Stop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Anomaly {
+    /// Drifted away from a target it had already reached, while printing
+    OffTarget,
+    /// Did not reach its target in time
+    SlowHeating,
+    /// Getting hotter while switched off
+    RisingWithoutTarget,
+    /// Fell by a lot between two readings, heater or thermistor trouble
+    SuddenDrop,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ThermalConfig {
+    /// Distance from target that counts as off target
+    pub band: f64,
+    pub off_target_after: Duration,
+    pub nozzle_heat_timeout: Duration,
+    pub bed_heat_timeout: Duration,
+    /// Rise with target 0 that is suspicious, within `rise_window`
+    pub rise_threshold: f64,
+    pub rise_window: Duration,
+    /// Drop between two readings that points to a fault
+    pub drop_threshold: f64,
+    /// Time between escalation steps while an anomaly persists
+    pub escalate_after: Duration,
+    pub off_target: ThermalAction,
+    pub slow_heating: ThermalAction,
+    pub rising_without_target: ThermalAction,
+    pub sudden_drop: ThermalAction,
+}
+
+impl ThermalConfig {
+    fn max_action(&self, anomaly: Anomaly) -> ThermalAction {
+        match anomaly {
+            Anomaly::OffTarget => self.off_target,
+            Anomaly::SlowHeating => self.slow_heating,
+            Anomaly::RisingWithoutTarget => self.rising_without_target,
+            Anomaly::SuddenDrop => self.sudden_drop,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ThermalAlert {
+    pub heater: &'static str,
+    pub anomaly: Anomaly,
+    pub action: ThermalAction,
+    pub message: String,
+}
+
+/// Watches the status stream for thermal trouble, time is passed in so
+/// recorded status sequences can be replayed
+pub struct ThermalWatchdog {
+    config: ThermalConfig,
+    heaters: [Heater; 2],
+}
+
+struct Heater {
+    name: &'static str,
+    heat_timeout: Duration,
+    target: f64,
+    target_set_at: Option<Instant>,
+    reached: bool,
+    off_target_since: Option<Instant>,
+    previous: Option<f64>,
+    /// Readings since the target went to 0, for spotting a rise
+    idle_readings: Vec<(Instant, f64)>,
+    dropped: bool,
+    active: Option<ActiveAnomaly>,
+}
+
+struct ActiveAnomaly {
+    anomaly: Anomaly,
+    action: ThermalAction,
+    escalated_at: Instant,
+}
+
+impl ThermalWatchdog {
+    pub fn new(config: ThermalConfig) -> Self {
+        Self {
+            heaters: [
+                Heater::new("Nozzle", config.nozzle_heat_timeout),
+                Heater::new("Bed", config.bed_heat_timeout),
+            ],
+            config,
+        }
+    }
+
+    pub fn observe(&mut self, status: &PrinterStatus, now: Instant) -> Vec<ThermalAlert> {
+        let readings = [
+            (status.nozzle_temperature, status.nozzle_target_temperature),
+            (status.heated_bed_temperature, status.heated_bed_target_temperature),
+        ];
+        let mut alerts = Vec::new();
+        for (heater, (actual, target)) in self.heaters.iter_mut().zip(readings) {
+            let detected = heater.detect(&self.config, actual, target, &status.status, now);
+            alerts.extend(heater.escalate(&self.config, detected, now));
+        }
+        alerts
+    }
+}
+
+impl Heater {
+    fn new(name: &'static str, heat_timeout: Duration) -> Self {
+        Self {
+            name,
+            heat_timeout,
+            target: 0.0,
+            target_set_at: None,
+            reached: false,
+            off_target_since: None,
+            previous: None,
+            idle_readings: Vec::new(),
+            dropped: false,
+            active: None,
+        }
+    }
+
+    fn detect(
+        &mut self,
+        config: &ThermalConfig,
+        actual: f64,
+        target: f64,
+        state: &str,
+        now: Instant,
+    ) -> Option<(Anomaly, String)> {
+        let name = self.name;
+        if target != self.target {
+            self.target = target;
+            self.target_set_at = (target > 0.0).then_some(now);
+            self.reached = false;
+            self.off_target_since = None;
+            self.idle_readings.clear();
+            self.dropped = false;
+        }
+        let previous = self.previous.replace(actual);
+        let within_band = (actual - target).abs() <= config.band;
+
+        if target <= 0.0 {
+            self.idle_readings.push((now, actual));
+            self.idle_readings
+                .retain(|(at, _)| now.duration_since(*at) <= config.rise_window);
+            let lowest = self
+                .idle_readings
+                .iter()
+                .map(|(_, x)| *x)
+                .fold(f64::INFINITY, f64::min);
+            return (actual - lowest > config.rise_threshold).then(|| {
+                (
+                    Anomaly::RisingWithoutTarget,
+                    format!("{name} rose to {actual:.1}°C from {lowest:.1}°C while switched off"),
+                )
+            });
+        }
+
+        if !self.reached {
+            if within_band {
+                self.reached = true;
+            } else {
+                let heating_for = self.target_set_at.map(|x| now.duration_since(x));
+                return heating_for
+                    .filter(|x| *x >= self.heat_timeout)
+                    .map(|x| {
+                        (
+                            Anomaly::SlowHeating,
+                            format!(
+                                "{name} is at {actual:.1}°C after heating to {target:.1}°C for {}s",
+                                x.as_secs()
+                            ),
+                        )
+                    });
+            }
+        }
+
+        if let Some(previous) = previous
+            && previous - actual > config.drop_threshold
+        {
+            self.dropped = true;
+        }
+        if self.dropped {
+            if actual >= target - config.band {
+                self.dropped = false;
+            } else {
+                return Some((
+                    Anomaly::SuddenDrop,
+                    format!("{name} dropped to {actual:.1}°C while holding {target:.1}°C"),
+                ));
+            }
+        }
+
+        // once it paused the print, keep watching so it can still step up to a stop
+        let escalating = self
+            .active
+            .as_ref()
+            .is_some_and(|x| x.anomaly == Anomaly::OffTarget);
+        let printing = state == "RUNNING" || (state == "PAUSED" && escalating);
+        if within_band || !printing {
+            self.off_target_since = None;
+            return None;
+        }
+        let since = *self.off_target_since.get_or_insert(now);
+        (now.duration_since(since) >= config.off_target_after).then(|| {
+            (
+                Anomaly::OffTarget,
+                format!(
+                    "{name} is at {actual:.1}°C instead of {target:.1}°C for {}s",
+                    now.duration_since(since).as_secs()
+                ),
+            )
+        })
+    }
+
+    /// Notifies once per anomaly, then steps up every `escalate_after` until
+    /// the configured action is reached
+    fn escalate(
+        &mut self,
+        config: &ThermalConfig,
+        detected: Option<(Anomaly, String)>,
+        now: Instant,
+    ) -> Option<ThermalAlert> {
+        let Some((anomaly, message)) = detected else {
+            self.active = None;
+            return None;
+        };
+        let action = match &mut self.active {
+            Some(active) if active.anomaly == anomaly => {
+                let next = match active.action {
+                    ThermalAction::Notify => ThermalAction::Pause,
+                    _ => ThermalAction::Stop,
+                };
+                // a stop is as far as it goes, don't send it again
+                if active.action == ThermalAction::Stop
+                    || next > config.max_action(anomaly)
+                    || now.duration_since(active.escalated_at) < config.escalate_after
+                {
+                    return None;
+                }
+                active.action = next;
+                active.escalated_at = now;
+                next
+            }
+            _ => {
+                self.active = Some(ActiveAnomaly {
+                    anomaly,
+                    action: ThermalAction::Notify,
+                    escalated_at: now,
+                });
+                ThermalAction::Notify
+            }
+        };
+        Some(ThermalAlert {
+            heater: self.name,
+            anomaly,
+            action,
+            message,
+        })
+    }
+}
+
+/// Lets the watchdog act on the printer, `None` only notifies
+pub(crate) struct WatchdogControl {
+    pub pairing: Arc<Pairing>,
+    pub audit: Arc<AuditLog>,
+}
+
+pub(crate) async fn thermal_watchdog_loop(
+    mut status_watch: watch::Receiver<PrinterStatus>,
+    event_sender: broadcast::Sender<PrinterEvent>,
+    control: Option<WatchdogControl>,
+) {
+    let Some(config) = THERMAL_WATCHDOG else {
+        return;
+    };
+    let mut watchdog = ThermalWatchdog::new(config);
+    while status_watch.changed().await.is_ok() {
+        let status = status_watch.borrow_and_update().clone();
+        // placeholders and old snapshots while the printer can't be reached carry no new readings
+        if !status.has_readings() {
+            continue;
+        }
+        for alert in watchdog.observe(&status, Instant::now()) {
+            let message = act(&alert, &status, control.as_ref()).await;
+            warn!("Thermal watchdog: {message}");
+            let _ = event_sender.send(PrinterEvent::new(
+                EventKind::TemperatureAnomaly,
+                message,
+                status.clone(),
+            ));
+        }
+    }
+}
+
+/// Carries out the alert's action, returns the message for the event
+async fn act(
+    alert: &ThermalAlert,
+    status: &PrinterStatus,
+    control: Option<&WatchdogControl>,
+) -> String {
+    let state = status.status.as_str();
+    let Some(control) = control else {
+        return alert.message.clone();
+    };
+    let Some(token) = control.pairing.token() else {
+        return alert.message.clone();
+    };
+    let (action, done, result) = match alert.action {
+        ThermalAction::Pause if state == "RUNNING" => (
+            "pause_print",
+            "paused the print",
+            snapmaker_client::pause_print(&token).await,
+        ),
+        ThermalAction::Stop if state == "RUNNING" || state == "PAUSED" => (
+            "stop_print",
+            "stopped the print",
+            snapmaker_client::stop_print(&token).await,
+        ),
+        _ => return alert.message.clone(),
+    };
+    control.audit.record_from(
+        "thermal-watchdog".to_string(),
+        None,
+        action,
+        json!({ "heater": alert.heater, "anomaly": format!("{:?}", alert.anomaly) }),
+        &result,
+    );
+    match result {
+        Ok(_) => format!("{}, {done}", alert.message),
+        Err(e) => {
+            error!("Thermal watchdog failed to {action}: {e:?}");
+            format!("{}, {action} failed: {e}", alert.message)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(max_action: ThermalAction) -> ThermalConfig {
+        ThermalConfig {
+            band: 15.0,
+            off_target_after: Duration::from_secs(60),
+            nozzle_heat_timeout: Duration::from_secs(300),
+            bed_heat_timeout: Duration::from_secs(600),
+            rise_threshold: 10.0,
+            rise_window: Duration::from_secs(60),
+            drop_threshold: 20.0,
+            escalate_after: Duration::from_secs(60),
+            off_target: max_action,
+            slow_heating: max_action,
+            rising_without_target: max_action,
+            sudden_drop: max_action,
+        }
+    }
+
+    /// A nozzle reading with the bed cold and switched off
+    fn nozzle(state: &str, actual: f64, target: f64) -> PrinterStatus {
+        PrinterStatus {
+            status: state.to_string(),
+            nozzle_temperature: actual,
+            nozzle_target_temperature: target,
+            heated_bed_temperature: 25.0,
+            ..Default::default()
+        }
+    }
+
+    /// Feeds `(seconds, status)` in order, returns when which alert was raised
+    fn replay(
+        max_action: ThermalAction,
+        steps: &[(u64, PrinterStatus)],
+    ) -> Vec<(u64, Anomaly, ThermalAction)> {
+        let mut watchdog = ThermalWatchdog::new(config(max_action));
+        let start = Instant::now();
+        steps
+            .iter()
+            .flat_map(|(at, status)| {
+                let now = start + Duration::from_secs(*at);
+                watchdog
+                    .observe(status, now)
+                    .into_iter()
+                    .map(move |x| (*at, x.anomaly, x.action))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn off_target_escalates_to_stop() {
+        let mut steps = vec![(0, nozzle("RUNNING", 200.0, 200.0))];
+        steps.extend((1..=13).map(|x| (x * 10, nozzle("RUNNING", 182.0, 200.0))));
+        // the pause lands on the printer
+        steps.extend((14..=30).map(|x| (x * 10, nozzle("PAUSED", 182.0, 200.0))));
+        assert_eq!(
+            replay(ThermalAction::Stop, &steps),
+            [
+                (70, Anomaly::OffTarget, ThermalAction::Notify),
+                (130, Anomaly::OffTarget, ThermalAction::Pause),
+                (190, Anomaly::OffTarget, ThermalAction::Stop),
+            ]
+        );
+    }
+
+    #[test]
+    fn off_target_ignored_while_idle() {
+        let steps = [
+            (0, nozzle("IDLE", 200.0, 200.0)),
+            (100, nozzle("IDLE", 182.0, 200.0)),
+            (200, nozzle("IDLE", 182.0, 200.0)),
+        ];
+        assert_eq!(replay(ThermalAction::Stop, &steps), []);
+    }
+
+    #[test]
+    fn off_target_ignored_while_paused_by_hand() {
+        let steps = [
+            (0, nozzle("RUNNING", 200.0, 200.0)),
+            (10, nozzle("PAUSED", 182.0, 200.0)),
+            (100, nozzle("PAUSED", 182.0, 200.0)),
+            (200, nozzle("PAUSED", 182.0, 200.0)),
+        ];
+        assert_eq!(replay(ThermalAction::Stop, &steps), []);
+    }
+
+    #[test]
+    fn escalation_stops_at_max_action() {
+        let mut steps = vec![(0, nozzle("RUNNING", 200.0, 200.0))];
+        steps.extend((1..=13).map(|x| (x * 10, nozzle("RUNNING", 182.0, 200.0))));
+        steps.extend((14..=30).map(|x| (x * 10, nozzle("PAUSED", 182.0, 200.0))));
+        assert_eq!(
+            replay(ThermalAction::Pause, &steps),
+            [
+                (70, Anomaly::OffTarget, ThermalAction::Notify),
+                (130, Anomaly::OffTarget, ThermalAction::Pause),
+            ]
+        );
+        assert_eq!(
+            replay(ThermalAction::Notify, &steps),
+            [(70, Anomaly::OffTarget, ThermalAction::Notify)]
+        );
+    }
+
+    #[test]
+    fn slow_heating() {
+        let steps = [
+            (0, nozzle("IDLE", 25.0, 200.0)),
+            (299, nozzle("IDLE", 150.0, 200.0)),
+            (300, nozzle("IDLE", 150.0, 200.0)),
+            (360, nozzle("IDLE", 160.0, 200.0)),
+        ];
+        assert_eq!(
+            replay(ThermalAction::Pause, &steps),
+            [
+                (300, Anomaly::SlowHeating, ThermalAction::Notify),
+                (360, Anomaly::SlowHeating, ThermalAction::Pause),
+            ]
+        );
+    }
+
+    #[test]
+    fn rising_without_target() {
+        let steps = [
+            (0, nozzle("IDLE", 25.0, 0.0)),
+            (10, nozzle("IDLE", 30.0, 0.0)),
+            (20, nozzle("IDLE", 36.0, 0.0)),
+        ];
+        assert_eq!(
+            replay(ThermalAction::Notify, &steps),
+            [(20, Anomaly::RisingWithoutTarget, ThermalAction::Notify)]
+        );
+    }
+
+    #[test]
+    fn rise_outside_the_window_is_fine() {
+        let steps = [
+            (0, nozzle("IDLE", 25.0, 0.0)),
+            (50, nozzle("IDLE", 30.0, 0.0)),
+            (100, nozzle("IDLE", 36.0, 0.0)),
+        ];
+        assert_eq!(replay(ThermalAction::Notify, &steps), []);
+    }
+
+    #[test]
+    fn sudden_drop() {
+        let steps = [
+            (0, nozzle("RUNNING", 200.0, 200.0)),
+            (1, nozzle("RUNNING", 175.0, 200.0)),
+            (2, nozzle("RUNNING", 176.0, 200.0)),
+        ];
+        assert_eq!(
+            replay(ThermalAction::Pause, &steps),
+            [(1, Anomaly::SuddenDrop, ThermalAction::Notify)]
+        );
+    }
+
+    #[test]
+    fn cleared_anomaly_starts_over() {
+        let steps = [
+            (0, nozzle("RUNNING", 200.0, 200.0)),
+            (10, nozzle("RUNNING", 182.0, 200.0)),
+            (70, nozzle("RUNNING", 182.0, 200.0)),
+            (130, nozzle("RUNNING", 182.0, 200.0)),
+            // back in the band, then off again
+            (140, nozzle("RUNNING", 195.0, 200.0)),
+            (150, nozzle("RUNNING", 182.0, 200.0)),
+            (200, nozzle("RUNNING", 182.0, 200.0)),
+            (210, nozzle("RUNNING", 182.0, 200.0)),
+        ];
+        assert_eq!(
+            replay(ThermalAction::Stop, &steps),
+            [
+                (70, Anomaly::OffTarget, ThermalAction::Notify),
+                (130, Anomaly::OffTarget, ThermalAction::Pause),
+                (210, Anomaly::OffTarget, ThermalAction::Notify),
+            ]
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufReader, Write},
+    path::Path,
+};
+
+use actix_web::{HttpRequest, HttpResponse, http::header};
+use log::info;
+use rustls::ServerConfig;
+
+use crate::config::{
+    TLS_ADDRESS, TLS_CERT_FILE, TLS_KEY_FILE, TLS_SELF_SIGNED, TLS_SELF_SIGNED_NAMES,
+};
+
+pub(crate) fn load_server_config() -> anyhow::Result<ServerConfig> {
+    load_or_generate(Path::new(TLS_CERT_FILE), Path::new(TLS_KEY_FILE), TLS_SELF_SIGNED)
+}
+
+fn load_or_generate(
+    cert_path: &Path,
+    key_path: &Path,
+    self_signed: bool,
+) -> anyhow::Result<ServerConfig> {
+    if !cert_path.exists() || !key_path.exists() {
+        if !self_signed {
+            anyhow::bail!(
+                "TLS certificate {} or key {} not found",
+                cert_path.display(),
+                key_path.display()
+            );
+        }
+        generate_self_signed(cert_path, key_path)?;
+    }
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {}", cert_path.display());
+    }
+    let key = match rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))? {
+        Some(x) => x,
+        None => anyhow::bail!("No private key found in {}", key_path.display()),
+    };
+
+    Ok(ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?)
+}
+
+fn generate_self_signed(cert_path: &Path, key_path: &Path) -> anyhow::Result<()> {
+    info!(
+        "Generating self-signed certificate for {:?}",
+        TLS_SELF_SIGNED_NAMES
+    );
+    let names: Vec<String> = TLS_SELF_SIGNED_NAMES
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+    let certified = rcgen::generate_simple_self_signed(names)?;
+
+    for path in [cert_path, key_path] {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    // created private, the key is never readable by others, not even for a moment
+    let _ = fs::remove_file(key_path);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(key_path)?
+        .write_all(certified.key_pair.serialize_pem().as_bytes())?;
+    fs::write(cert_path, certified.cert.pem())?;
+    Ok(())
+}
+
+/// Fallback service for the plain HTTP listener when everything should go over HTTPS
+pub(crate) async fn redirect_to_https(req: HttpRequest) -> HttpResponse {
+    let connection_info = req.connection_info();
+    let host = connection_info.host();
+    // strip the port of the plain listener, keeping IPv6 literals intact
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    };
+    let port = TLS_ADDRESS
+        .and_then(|x| x.rsplit_once(':'))
+        .map(|(_, port)| port)
+        .unwrap_or("443");
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|x| x.as_str())
+        .unwrap_or("/");
+
+    let location = if port == "443" {
+        format!("https://{host}{path}")
+    } else {
+        format!("https://{host}:{port}{path}")
+    };
+    HttpResponse::PermanentRedirect()
+        .insert_header((header::LOCATION, location))
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::test::TestRequest;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConnection};
+
+    use super::*;
+
+    /// Runs a TLS handshake in memory, as a client trusting only `cert_path`
+    fn handshake(server_config: ServerConfig, cert_path: &Path) -> anyhow::Result<()> {
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?)) {
+            roots.add(cert?)?;
+        }
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), "localhost".try_into()?)?;
+        let mut server = ServerConnection::new(Arc::new(server_config))?;
+        for _ in 0..10 {
+            if !client.is_handshaking() && !server.is_handshaking() {
+                return Ok(());
+            }
+            let mut buffer = Vec::new();
+            client.write_tls(&mut buffer)?;
+            server.read_tls(&mut buffer.as_slice())?;
+            server.process_new_packets()?;
+            let mut buffer = Vec::new();
+            server.write_tls(&mut buffer)?;
+            client.read_tls(&mut buffer.as_slice())?;
+            client.process_new_packets()?;
+        }
+        anyhow::bail!("Handshake didn't finish")
+    }
+
+    #[test]
+    fn generates_and_keeps_a_self_signed_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("tls/cert.pem");
+        let key_path = dir.path().join("tls/key.pem");
+
+        let config = load_or_generate(&cert_path, &key_path, true).unwrap();
+        handshake(config, &cert_path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&key_path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // the next start uses the same certificate, browsers keep trusting it
+        let generated = fs::read(&cert_path).unwrap();
+        let config = load_or_generate(&cert_path, &key_path, true).unwrap();
+        assert_eq!(fs::read(&cert_path).unwrap(), generated);
+        handshake(config, &cert_path).unwrap();
+    }
+
+    #[test]
+    fn replaces_a_key_left_without_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        fs::write(&key_path, "stale").unwrap();
+
+        let config = load_or_generate(&cert_path, &key_path, true).unwrap();
+        handshake(config, &cert_path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&key_path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn missing_certificate_without_self_signing() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        assert!(load_or_generate(&cert_path, &key_path, false).is_err());
+        assert!(!cert_path.exists());
+    }
+
+    #[test]
+    fn empty_certificate_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        fs::write(&cert_path, "").unwrap();
+        fs::write(&key_path, "").unwrap();
+        let error = load_or_generate(&cert_path, &key_path, true).unwrap_err();
+        assert!(error.to_string().starts_with("No certificates found"));
+    }
+
+    async fn location(host: &str, uri: &str) -> String {
+        let req = TestRequest::get()
+            .uri(uri)
+            .insert_header((header::HOST, host))
+            .to_http_request();
+        let response = redirect_to_https(req).await;
+        assert_eq!(response.status(), 308);
+        response.headers().get(header::LOCATION).unwrap().to_str().unwrap().to_string()
+    }
+
+    #[actix_web::test]
+    async fn redirects_to_https() {
+        assert_eq!(
+            location("printer.local:55533", "/api/status?x=1").await,
+            "https://printer.local/api/status?x=1"
+        );
+        assert_eq!(location("[::1]:55533", "/").await, "https://[::1]/");
+        assert_eq!(location("[::1]", "/").await, "https://[::1]/");
+    }
+}
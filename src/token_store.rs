@@ -0,0 +1,328 @@
+use std::{
+    env,
+    fs::{self, DirBuilder, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, anyhow, bail};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chacha20poly1305::{
+    ChaCha20Poly1305, KeyInit, Nonce,
+    aead::{Aead, Payload},
+};
+use log::{info, warn};
+
+use crate::config::{DISCOVER_PRINTER, SNAPMAKER_ENDPOINT, STATE_DIR, TOKEN_KEY_FILE};
+
+/// Where tokens were kept before, moved into the state directory when found
+const LEGACY_TOKEN_FILE: &str = "snapmaker_token.txt";
+/// Marks an encrypted token file, followed by base64 of nonce and ciphertext
+const ENCRYPTED_PREFIX: &str = "sm-proxy-encrypted:v1:";
+/// Takes precedence over `TOKEN_KEY_FILE`, 64 hex digits
+const KEY_VARIABLE: &str = "SM_PROXY_TOKEN_KEY";
+const NONCE_LENGTH: usize = 12;
+
+/// `STATE_DIR`, systemd's `StateDirectory=`, then the XDG state directory
+pub fn state_dir() -> PathBuf {
+    if let Some(dir) = STATE_DIR {
+        return PathBuf::from(dir);
+    }
+    // systemd may pass several, separated by colons
+    if let Ok(dirs) = env::var("STATE_DIRECTORY")
+        && let Some(dir) = dirs.split(':').find(|x| !x.is_empty())
+    {
+        return PathBuf::from(dir);
+    }
+    if let Ok(dir) = env::var("XDG_STATE_HOME")
+        && !dir.is_empty()
+    {
+        return Path::new(&dir).join("sm-proxy");
+    }
+    match env::var("HOME") {
+        Ok(home) => Path::new(&home).join(".local/state/sm-proxy"),
+        Err(_) => PathBuf::from("."),
+    }
+}
+
+/// One file per printer, so proxies for different printers can share the directory
+pub fn token_path() -> PathBuf {
+    let printer: String = printer()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' { c } else { '_' })
+        .collect();
+    state_dir().join(format!("token-{printer}"))
+}
+
+/// The followed printer's name, its address would change with DHCP
+fn printer() -> &'static str {
+    match DISCOVER_PRINTER {
+        Some(name) => name,
+        None => SNAPMAKER_ENDPOINT.split("://").last().unwrap_or_default().trim_end_matches('/'),
+    }
+}
+
+/// The stored token, `None` when there is none yet
+pub fn load() -> anyhow::Result<Option<String>> {
+    Store::configured()?.load(Path::new(LEGACY_TOKEN_FILE))
+}
+
+/// Replaces the stored token, readable by this user only
+pub fn save(token: &str) -> anyhow::Result<()> {
+    Store::configured()?.save(token)
+}
+
+/// Time since the token was last stored
+pub fn age() -> Option<Duration> {
+    fs::metadata(token_path()).ok()?.modified().ok()?.elapsed().ok()
+}
+
+pub fn encrypted() -> bool {
+    matches!(key(), Ok(Some(_)))
+}
+
+/// A token file with everything that decides how it is read and written
+struct Store<'a> {
+    path: PathBuf,
+    /// Bound into the encryption
+    printer: &'a str,
+    key: Option<ChaCha20Poly1305>,
+}
+
+impl Store<'static> {
+    fn configured() -> anyhow::Result<Self> {
+        Ok(Self {
+            path: token_path(),
+            printer: printer(),
+            key: key()?,
+        })
+    }
+}
+
+impl Store<'_> {
+    fn load(&self, legacy_file: &Path) -> anyhow::Result<Option<String>> {
+        let path = &self.path;
+        let contents = match fs::read_to_string(path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return self.migrate_legacy(legacy_file);
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let contents = contents.trim();
+        let Some(encrypted) = contents.strip_prefix(ENCRYPTED_PREFIX) else {
+            if self.key.is_some() {
+                // a key was configured after the token was stored
+                info!("Encrypting the stored token");
+                self.save(contents)?;
+            }
+            return Ok(Some(contents.to_string()));
+        };
+        let Some(key) = &self.key else {
+            bail!("{} is encrypted, set {KEY_VARIABLE} or TOKEN_KEY_FILE", path.display());
+        };
+        decrypt(key, self.printer, encrypted).map(Some)
+    }
+
+    fn save(&self, token: &str) -> anyhow::Result<()> {
+        let path = &self.path;
+        let contents = match &self.key {
+            Some(key) => format!("{ENCRYPTED_PREFIX}{}", encrypt(key, self.printer, token)?),
+            None => token.to_string(),
+        };
+        if let Some(dir) = path.parent() {
+            let mut builder = DirBuilder::new();
+            builder.recursive(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::DirBuilderExt;
+                builder.mode(0o700);
+            }
+            builder
+                .create(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let temporary = temporary_path(path);
+        let _ = fs::remove_file(&temporary);
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options
+            .open(&temporary)
+            .with_context(|| format!("Failed to create {}", temporary.display()))?;
+        file.write_all(contents.as_bytes())
+            .and_then(|_| file.sync_all())
+            .with_context(|| format!("Failed to write {}", temporary.display()))?;
+        fs::rename(&temporary, path)
+            .with_context(|| format!("Failed to replace {}", path.display()))
+    }
+
+    fn migrate_legacy(&self, legacy_file: &Path) -> anyhow::Result<Option<String>> {
+        let Ok(token) = fs::read_to_string(legacy_file) else {
+            return Ok(None);
+        };
+        let token = token.trim().to_string();
+        self.save(&token)?;
+        info!("Moved {} to {}", legacy_file.display(), self.path.display());
+        if let Err(e) = fs::remove_file(legacy_file) {
+            warn!("Failed to remove {}: {e}", legacy_file.display());
+        }
+        Ok(Some(token))
+    }
+}
+
+/// Written next to the file and renamed, a crash never leaves half a token behind
+fn temporary_path(path: &Path) -> PathBuf {
+    // appended, the printer's address in the name would lose its last part to an extension
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".tmp");
+    path.with_file_name(file_name)
+}
+
+fn key() -> anyhow::Result<Option<ChaCha20Poly1305>> {
+    let hex_key = match (env::var(KEY_VARIABLE), TOKEN_KEY_FILE) {
+        (Ok(x), _) => x,
+        (Err(_), Some(file)) => {
+            fs::read_to_string(file).with_context(|| format!("Failed to read {file}"))?
+        }
+        (Err(_), None) => return Ok(None),
+    };
+    parse_key(&hex_key).map(Some)
+}
+
+fn parse_key(hex_key: &str) -> anyhow::Result<ChaCha20Poly1305> {
+    let bytes = hex::decode(hex_key.trim()).context("The token key isn't hex")?;
+    ChaCha20Poly1305::new_from_slice(&bytes)
+        .map_err(|_| anyhow!("The token key must be 32 bytes, 64 hex digits"))
+}
+
+/// The printer is bound in, a file copied from another printer doesn't decrypt
+fn encrypt(key: &ChaCha20Poly1305, printer: &str, token: &str) -> anyhow::Result<String> {
+    let nonce: [u8; NONCE_LENGTH] = rand::random();
+    let payload = Payload {
+        msg: token.as_bytes(),
+        aad: printer.as_bytes(),
+    };
+    let ciphertext = key
+        .encrypt(Nonce::from_slice(&nonce), payload)
+        .map_err(|_| anyhow!("Failed to encrypt the token"))?;
+    Ok(STANDARD.encode([&nonce[..], &ciphertext].concat()))
+}
+
+fn decrypt(key: &ChaCha20Poly1305, printer: &str, encoded: &str) -> anyhow::Result<String> {
+    let bytes = STANDARD.decode(encoded).context("The stored token is damaged")?;
+    if bytes.len() < NONCE_LENGTH {
+        bail!("The stored token is damaged");
+    }
+    let (nonce, ciphertext) = bytes.split_at(NONCE_LENGTH);
+    let payload = Payload {
+        msg: ciphertext,
+        aad: printer.as_bytes(),
+    };
+    let token = key
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| anyhow!("Failed to decrypt the stored token, wrong key or another printer"))?;
+    String::from_utf8(token).context("The stored token is damaged")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn store<'a>(dir: &Path, printer: &'a str, key: Option<&str>) -> Store<'a> {
+        Store {
+            path: dir.join("state/token-printer"),
+            printer,
+            key: key.map(|x| parse_key(x).unwrap()),
+        }
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(dir.path(), "printer", Some(KEY));
+        store.save("secret-token").unwrap();
+
+        let contents = fs::read_to_string(&store.path).unwrap();
+        assert!(contents.starts_with(ENCRYPTED_PREFIX));
+        assert!(!contents.contains("secret-token"));
+        let legacy = dir.path().join("legacy");
+        assert_eq!(store.load(&legacy).unwrap().as_deref(), Some("secret-token"));
+    }
+
+    #[test]
+    fn wrong_key_or_printer_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let legacy = dir.path().join("legacy");
+        store(dir.path(), "printer", Some(KEY)).save("secret-token").unwrap();
+
+        let other_key = KEY.replace("00", "ff");
+        assert!(store(dir.path(), "printer", Some(&other_key)).load(&legacy).is_err());
+        assert!(store(dir.path(), "other-printer", Some(KEY)).load(&legacy).is_err());
+        assert!(store(dir.path(), "printer", None).load(&legacy).is_err());
+    }
+
+    #[test]
+    fn plaintext_is_encrypted_once_a_key_is_set() {
+        let dir = tempfile::tempdir().unwrap();
+        let legacy = dir.path().join("legacy");
+        store(dir.path(), "printer", None).save("secret-token").unwrap();
+        let store = store(dir.path(), "printer", Some(KEY));
+        assert_eq!(fs::read_to_string(&store.path).unwrap(), "secret-token");
+
+        assert_eq!(store.load(&legacy).unwrap().as_deref(), Some("secret-token"));
+        let contents = fs::read_to_string(&store.path).unwrap();
+        assert!(contents.starts_with(ENCRYPTED_PREFIX));
+        assert_eq!(store.load(&legacy).unwrap().as_deref(), Some("secret-token"));
+    }
+
+    #[test]
+    fn legacy_file_is_moved() {
+        let dir = tempfile::tempdir().unwrap();
+        let legacy = dir.path().join("snapmaker_token.txt");
+        let store = store(dir.path(), "printer", None);
+        assert_eq!(store.load(&legacy).unwrap(), None);
+
+        fs::write(&legacy, "secret-token\n").unwrap();
+        assert_eq!(store.load(&legacy).unwrap().as_deref(), Some("secret-token"));
+        assert!(!legacy.exists());
+        assert_eq!(fs::read_to_string(&store.path).unwrap(), "secret-token");
+    }
+
+    #[test]
+    fn temporary_file_keeps_the_address() {
+        assert_eq!(
+            temporary_path(Path::new("/state/token-192.168.1.20")),
+            Path::new("/state/token-192.168.1.20.tmp")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_readable_by_the_user() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(dir.path(), "printer", None);
+        store.save("secret-token").unwrap();
+        store.save("new-token").unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&store.path), 0o600);
+        assert_eq!(mode(store.path.parent().unwrap()), 0o700);
+        assert!(!temporary_path(&store.path).exists());
+    }
+
+    #[test]
+    fn key_must_be_32_bytes_of_hex() {
+        assert!(parse_key(KEY).is_ok());
+        assert!(parse_key(&KEY[2..]).is_err());
+        assert!(parse_key("not hex").is_err());
+    }
+}
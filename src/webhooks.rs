@@ -0,0 +1,324 @@
+use std::{fs::OpenOptions, io::Write, time::Duration};
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use rand::{Rng, distributions::Alphanumeric};
+use serde::Serialize;
+use serde_json::{Value, json};
+use reqwest::StatusCode;
+use sha2::Sha256;
+use tokio::sync::broadcast;
+
+use crate::{
+    config::{PRINTER_NAME, WEBHOOK_LOG_FILE, WEBHOOK_MAX_ATTEMPTS, WEBHOOKS},
+    events::{EventKind, PrinterEvent},
+};
+
+/// Shape of the request body a webhook target expects
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(
+    not(test),
+    allow(dead_code, reason = "constructed by `WEBHOOKS` in config.rs, which is empty by default")
+)]
+pub enum WebhookFormat {
+    /// The full event including the status snapshot
+    Json,
+    Discord,
+    Slack,
+    /// Plain text body with a `Title` header, see https://docs.ntfy.sh/publish/
+    Ntfy,
+    /// Tera template rendered with `event`, `title`, `message`, `printer`, `timestamp` and `status`
+    Template(&'static str),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WebhookTarget {
+    pub url: &'static str,
+    /// Signs the body with HMAC-SHA256 into the `X-Signature-256` header
+    pub secret: Option<&'static str>,
+    pub format: WebhookFormat,
+    /// Empty means every event
+    pub events: &'static [EventKind],
+}
+
+#[derive(Debug, Serialize)]
+struct DeliveryLogEntry<'a> {
+    timestamp: DateTime<Utc>,
+    delivery: &'a str,
+    target: String,
+    event: EventKind,
+    attempt: u32,
+    status: Option<u16>,
+    error: Option<String>,
+    delivered: bool,
+}
+
+pub(crate) async fn webhook_loop(mut events: broadcast::Receiver<PrinterEvent>) {
+    if WEBHOOKS.is_empty() {
+        return;
+    }
+    loop {
+        let event = match events.recv().await {
+            Ok(x) => x,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("Webhooks skipped {skipped} events");
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        for target in WEBHOOKS {
+            if target.events.is_empty() || target.events.contains(&event.kind) {
+                tokio::spawn(deliver(*target, event.clone()));
+            }
+        }
+    }
+}
+
+async fn deliver(target: WebhookTarget, event: PrinterEvent) {
+    let (body, content_type) = match render_body(&target.format, &event) {
+        Ok(x) => x,
+        Err(e) => {
+            error!(
+                "Failed to render webhook body for {}: {e}",
+                target_name(&target)
+            );
+            return;
+        }
+    };
+    let delivery: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(12)
+        .map(char::from)
+        .collect();
+    let client = reqwest::Client::new();
+
+    for attempt in 1..=WEBHOOK_MAX_ATTEMPTS {
+        let mut request = client
+            .post(target.url)
+            .timeout(Duration::from_secs(10))
+            .header("Content-Type", content_type)
+            .header("X-SM-Proxy-Event", event_name(event.kind))
+            .header("X-SM-Proxy-Delivery", &delivery)
+            .body(body.clone());
+        if let WebhookFormat::Ntfy = target.format {
+            request = request.header("Title", event.kind.title());
+        }
+        if let Some(secret) = target.secret {
+            request = request.header("X-Signature-256", sign(secret, &body));
+        }
+
+        let (status, error, retry) = match request.send().await {
+            Ok(response) if response.status().is_success() => {
+                (Some(response.status().as_u16()), None, false)
+            }
+            Ok(response) => {
+                let status = response.status();
+                let text = response.text().await.unwrap_or_default();
+                (Some(status.as_u16()), Some(text), retryable(status))
+            }
+            Err(e) => (None, Some(e.to_string()), true),
+        };
+        let delivered = error.is_none();
+        log_delivery(DeliveryLogEntry {
+            timestamp: Utc::now(),
+            delivery: &delivery,
+            target: target_name(&target),
+            event: event.kind,
+            attempt,
+            status,
+            error,
+            delivered,
+        });
+        if delivered {
+            info!(
+                "Delivered {:?} webhook to {}",
+                event.kind,
+                target_name(&target)
+            );
+            return;
+        }
+        if !retry {
+            error!(
+                "{} refused the {:?} webhook, not retrying",
+                target_name(&target),
+                event.kind
+            );
+            return;
+        }
+        if attempt < WEBHOOK_MAX_ATTEMPTS {
+            let backoff = Duration::from_secs((1u64 << (attempt - 1)).min(60));
+            tokio::time::sleep(backoff).await;
+        }
+    }
+    error!(
+        "Giving up delivering {:?} webhook to {} after {WEBHOOK_MAX_ATTEMPTS} attempts",
+        event.kind,
+        target_name(&target)
+    );
+}
+
+fn render_body(
+    format: &WebhookFormat,
+    event: &PrinterEvent,
+) -> anyhow::Result<(String, &'static str)> {
+    let title = event.kind.title();
+    let body = match format {
+        WebhookFormat::Json => (
+            json!({
+                "event": event.kind,
+                "title": title,
+                "message": event.message,
+                "printer": PRINTER_NAME,
+                "timestamp": event.timestamp,
+                "status": event.status,
+            })
+            .to_string(),
+            "application/json",
+        ),
+        WebhookFormat::Discord => (
+            json!({ "content": format!("**{PRINTER_NAME}: {title}**\n{}", event.message) })
+                .to_string(),
+            "application/json",
+        ),
+        WebhookFormat::Slack => (
+            json!({ "text": format!("*{PRINTER_NAME}: {title}*\n{}", event.message) }).to_string(),
+            "application/json",
+        ),
+        WebhookFormat::Ntfy => (event.message.clone(), "text/plain"),
+        WebhookFormat::Template(template) => {
+            let mut context = tera::Context::new();
+            context.insert("event", &event.kind);
+            context.insert("title", title);
+            context.insert("message", &event.message);
+            context.insert("printer", PRINTER_NAME);
+            context.insert("timestamp", &event.timestamp);
+            context.insert("status", &event.status);
+            let body = tera::Tera::one_off(template, &context, false)?;
+            // templates usually produce JSON, but don't claim so if they don't
+            let content_type = match serde_json::from_str::<Value>(&body) {
+                Ok(_) => "application/json",
+                Err(_) => "text/plain",
+            };
+            (body, content_type)
+        }
+    };
+    Ok(body)
+}
+
+/// A rejected request is sent again only if the target may accept it later
+fn retryable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+fn event_name(kind: EventKind) -> String {
+    match serde_json::to_value(kind) {
+        Ok(Value::String(x)) => x,
+        _ => format!("{kind:?}"),
+    }
+}
+
+/// Webhook URLs often carry credentials in their path, only log the host
+pub(crate) fn target_name(target: &WebhookTarget) -> String {
+    match reqwest::Url::parse(target.url) {
+        Ok(url) => format!("{}://{}", url.scheme(), url.host_str().unwrap_or_default()),
+        Err(_) => "invalid url".to_string(),
+    }
+}
+
+fn log_delivery(entry: DeliveryLogEntry) {
+    let written = serde_json::to_string(&entry)
+        .map_err(std::io::Error::other)
+        .and_then(|line| {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(WEBHOOK_LOG_FILE)?;
+            writeln!(file, "{line}")
+        });
+    if let Err(e) = written {
+        error!("Failed to write webhook delivery log {WEBHOOK_LOG_FILE}: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::PrinterStatus;
+
+    fn finished() -> PrinterEvent {
+        PrinterEvent::new(
+            EventKind::PrintFinished,
+            "bracket.gcode finished after 1h 2m".to_string(),
+            PrinterStatus {
+                status: "IDLE".to_string(),
+                file_name: "bracket.gcode".to_string(),
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn renders_chat_bodies() {
+        let event = finished();
+        let (body, content_type) = render_body(&WebhookFormat::Discord, &event).unwrap();
+        assert_eq!(content_type, "application/json");
+        let content = "**snapmaker: Print finished**\nbracket.gcode finished after 1h 2m";
+        assert_eq!(serde_json::from_str::<Value>(&body).unwrap(), json!({ "content": content }));
+
+        let (body, content_type) = render_body(&WebhookFormat::Slack, &event).unwrap();
+        assert_eq!(content_type, "application/json");
+        let text = "*snapmaker: Print finished*\nbracket.gcode finished after 1h 2m";
+        assert_eq!(serde_json::from_str::<Value>(&body).unwrap(), json!({ "text": text }));
+
+        let (body, content_type) = render_body(&WebhookFormat::Ntfy, &event).unwrap();
+        assert_eq!(content_type, "text/plain");
+        assert_eq!(body, "bracket.gcode finished after 1h 2m");
+    }
+
+    #[test]
+    fn renders_json_and_templates() {
+        let event = finished();
+        let (body, content_type) = render_body(&WebhookFormat::Json, &event).unwrap();
+        assert_eq!(content_type, "application/json");
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["event"], "print_finished");
+        assert_eq!(body["status"]["file_name"], "bracket.gcode");
+
+        let template = WebhookFormat::Template(r#"{"text": "{{ printer }}: {{ title }}"}"#);
+        let (body, content_type) = render_body(&template, &event).unwrap();
+        assert_eq!(content_type, "application/json");
+        assert_eq!(body, r#"{"text": "snapmaker: Print finished"}"#);
+
+        let template = WebhookFormat::Template("{{ title }} ({{ event }})");
+        let (body, content_type) = render_body(&template, &event).unwrap();
+        assert_eq!(content_type, "text/plain");
+        assert_eq!(body, "Print finished (print_finished)");
+    }
+
+    #[test]
+    fn retries_only_what_may_succeed_later() {
+        assert!(retryable(StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(retryable(StatusCode::BAD_GATEWAY));
+        assert!(retryable(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!retryable(StatusCode::BAD_REQUEST));
+        assert!(!retryable(StatusCode::UNAUTHORIZED));
+        assert!(!retryable(StatusCode::NOT_FOUND));
+    }
+}